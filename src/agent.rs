@@ -0,0 +1,105 @@
+use std::fmt;
+
+use rand::Rng;
+
+use compact::{decode_card, knowledge_allows, CompactState, RANK_KNOWLEDGE, SUIT_KNOWLEDGE};
+use hanabi::{Action, Card, GameState, PLAYERS};
+use rollout::clue_is_new;
+
+// A complete strategy for playing the game: given the view of the player to
+// move, pick that player's action. Agents that search use the rng for their
+// samples, so a seeded rng makes them reproducible.
+
+pub trait Agent: fmt::Debug + Send + Sync {
+    fn choose_action(&self, view: &GameState, rng: &mut dyn Rng) -> Action;
+}
+
+// The cards the clues on a card still allow it to be.
+pub(crate) fn possible_cards(state: &CompactState, c_id: u8) -> impl Iterator<Item = Card> {
+    let knowledge = state.knowledge(c_id);
+    (0..25).map(decode_card).filter(move |&c| knowledge_allows(knowledge, c))
+}
+
+// Whether a clue has named the card's suit or rank. Negative information
+// alone rarely narrows a dimension down to one value.
+pub(crate) fn is_clued(state: &CompactState, c_id: u8) -> bool {
+    let knowledge = state.knowledge(c_id);
+    (knowledge & SUIT_KNOWLEDGE).count_ones() == 1 || (knowledge & RANK_KNOWLEDGE).count_ones() == 1
+}
+
+// How many seats after the actor a player sits, from 1 to 3.
+pub(crate) fn seat_distance(state: &CompactState, action: Action) -> usize {
+    match action {
+        Action::Clue(target, _) => (target.index() + PLAYERS.len() - state.current_player().index()) % PLAYERS.len(),
+        _ => 0,
+    }
+}
+
+// A simple deterministic strategy that only uses what the actor can see, in
+// order of preference:
+//  1. play the oldest card the actor knows is playable,
+//  2. tell the nearest partner something new about a playable card they do
+//     not know about, touching as few cards as possible,
+//  3. discard the oldest card the actor knows is dead, or else the oldest
+//     card without clues,
+//  4. give the nearest partner a clue with new information,
+//  5. discard the oldest card, or play it if discarding is not allowed.
+// It makes a reasonable blueprint for agents that search on top of one.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeuristicAgent;
+
+impl Agent for HeuristicAgent {
+    fn choose_action(&self, view: &GameState, _rng: &mut dyn Rng) -> Action {
+        let state = CompactState::from(view);
+        let hand = state.hand(state.current_player());
+        let legal_actions = state.legal_actions();
+
+        if let Some(i) = hand.iter().position(|&c_id| state.known_playable(c_id)) {
+            return Action::Play(i);
+        }
+
+        let touched = |action: Action| -> Vec<u8> {
+            match action {
+                Action::Clue(target, clue) => state.hand(target).iter().cloned()
+                    .filter(|&c_id| state.card(c_id).is_some_and(|c| clue.matches(c)))
+                    .collect(),
+                _ => Vec::new(),
+            }
+        };
+        let play_clue = legal_actions.iter().cloned()
+            .filter(|&action| match action {
+                Action::Clue(_, clue) => touched(action).iter().any(|&c_id| {
+                    state.card(c_id).is_some_and(|c| state.is_playable(c)) && clue_is_new(clue, state.knowledge(c_id))
+                }),
+                _ => false,
+            })
+            .min_by_key(|&action| (seat_distance(&state, action), touched(action).len()));
+        if let Some(action) = play_clue {
+            return action;
+        }
+
+        if state.clues() < 8 {
+            let dead = hand.iter().position(|&c_id| possible_cards(&state, c_id).all(|c| state.is_dead(c)));
+            let unclued = hand.iter().position(|&c_id| !is_clued(&state, c_id));
+            if let Some(i) = dead.or(unclued) {
+                return Action::Discard(i);
+            }
+        }
+
+        let new_clue = legal_actions.iter().cloned()
+            .filter(|&action| match action {
+                Action::Clue(_, clue) => touched(action).iter().any(|&c_id| clue_is_new(clue, state.knowledge(c_id))),
+                _ => false,
+            })
+            .min_by_key(|&action| seat_distance(&state, action));
+        if let Some(action) = new_clue {
+            return action;
+        }
+
+        if state.clues() < 8 {
+            Action::Discard(0)
+        } else {
+            Action::Play(0)
+        }
+    }
+}
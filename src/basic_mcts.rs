@@ -1,15 +1,41 @@
 use std::collections::HashMap;
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
+use std::mem;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
+use rand;
 use rand::Rng;
 use rand::distributions::{IndependentSample, Range};
+use rayon;
+use rayon::prelude::*;
 
-use hanabi::{Action, ActionResult, GameState};
+use compact::{CompactResult, CompactState};
+use endgame::{EndgameConfig, EndgameSolution, EndgameSolver};
+use hanabi::{Action, ActionResult, Card, CardId, CompletedAction, GameState, Player, PLAYERS};
+use particle_filter::ParticleFilter;
+use evaluation::{Evaluator, PaceEvaluator};
+use prior::{ActionPrior, HeuristicPrior};
+use rollout::{rollout, RolloutPolicy, UniformRollout};
 
-struct Arrow {
-    expected_reward: f64,
-    num_samples: f64,
+const MAX_SCORE: f64 = 25.0;
+const MIN_VARIANCE_SAMPLES: f64 = 30.0;
+
+// Virtual losses are playouts that have selected an arrow but not yet
+// reported a result. They count as samples with the lowest possible reward,
+// so that concurrent playouts spread out instead of all following the same
+// path.
+pub(crate) struct Arrow {
+    pub(crate) expected_reward: f64,
+    pub(crate) num_samples: f64,
+    // The sum of squared deviations from the mean (Welford's method), for
+    // the variance used by UCB1-Tuned.
+    squared_deviations: f64,
+    pub(crate) virtual_losses: f64,
+    // All-moves-as-first statistics: the results of playouts in which the
+    // acting player took this action at any later point, not just here.
+    pub(crate) amaf_reward: f64,
+    pub(crate) amaf_samples: f64,
 }
 
 impl Arrow {
@@ -17,43 +43,193 @@ impl Arrow {
         Arrow {
             expected_reward: 0.0,
             num_samples: 0.0,
+            squared_deviations: 0.0,
+            virtual_losses: 0.0,
+            amaf_reward: 0.0,
+            amaf_samples: 0.0,
         }
     }
 
     fn add_sample(&mut self, reward: f64) {
+        let old_expectation = self.expected_reward;
         let new_expectation = (self.expected_reward * self.num_samples + reward) / (self.num_samples + 1.0);
         self.expected_reward = new_expectation;
         self.num_samples += 1.0;
+        self.squared_deviations += (reward - old_expectation) * (reward - new_expectation);
+    }
+
+    fn add_amaf_sample(&mut self, reward: f64) {
+        self.amaf_reward = (self.amaf_reward * self.amaf_samples + reward) / (self.amaf_samples + 1.0);
+        self.amaf_samples += 1.0;
+    }
+
+    // The mean reward blended with the AMAF mean as in RAVE, with weight
+    // beta = sqrt(k / (3n + k)) on the AMAF mean for rave equivalence k
+    // (Gelly and Silver 2007). The AMAF mean dominates for the first few
+    // samples and fades out once n is well past k.
+    pub(crate) fn blended_reward(&self, mean: f64, num_samples: f64, rave_equivalence: Option<f64>) -> f64 {
+        match rave_equivalence {
+            Some(k) if self.amaf_samples > 0.0 => {
+                let beta = (k / (3.0 * num_samples + k)).sqrt();
+                (1.0 - beta) * mean + beta * self.amaf_reward
+            },
+            _ => mean,
+        }
+    }
+
+    // Half the width of a normal-approximation confidence interval on the
+    // mean, confidence standard errors to each side. The sample variance of a
+    // handful of results can be far too small, even zero, so below
+    // MIN_VARIANCE_SAMPLES the largest variance rewards spanning
+    // reward_range could have is used instead.
+    fn confidence_radius(&self, confidence: f64, reward_range: f64) -> f64 {
+        if self.num_samples == 0.0 {
+            return f64::INFINITY;
+        }
+        let variance = if self.num_samples < MIN_VARIANCE_SAMPLES {
+            reward_range * reward_range / 4.0
+        } else {
+            self.variance()
+        };
+        confidence * (variance / self.num_samples).sqrt()
+    }
+
+    // How highly a final move policy rates the arrow.
+    fn final_grade(&self, policy: FinalMovePolicy, confidence: f64, reward_range: f64) -> f64 {
+        match policy {
+            FinalMovePolicy::MaxChild => self.expected_reward,
+            FinalMovePolicy::RobustChild => self.num_samples,
+            FinalMovePolicy::SecureChild => self.expected_reward - self.confidence_radius(confidence, reward_range),
+        }
+    }
+
+    fn variance(&self) -> f64 {
+        if self.num_samples > 0.0 {
+            self.squared_deviations / self.num_samples
+        } else {
+            0.0
+        }
     }
 }
 
-struct Node {
-    actions: HashMap<Action, Arrow>,
-    total_samples: f64,
+// The formula Node::select uses to trade off exploration and exploitation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelectionRule {
+    // mean + c * sqrt(ln N / n)
+    Ucb1,
+    // UCB1 with the exploration term scaled by the arrow's observed variance
+    // (Auer et al. 2002): mean + c * sqrt(ln N / n * min(1/4, V)), where
+    // V = variance + sqrt(2 ln N / n).
+    Ucb1Tuned,
+    // mean + c * prior * sqrt(N) / (1 + n), as in AlphaZero. Unvisited
+    // actions are not forced to be tried first.
+    Puct,
+}
+
+// Everything Node::select needs besides the node's statistics.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Selection<'a> {
+    pub(crate) rule: SelectionRule,
+    pub(crate) exploration: f64,
+    // Rewards are mapped to (reward - reward_offset) / reward_scale before
+    // the formula is applied, so that the exploration constant does not
+    // depend on the range of rewards.
+    pub(crate) reward_offset: f64,
+    pub(crate) reward_scale: f64,
+    // One per legal action, used only by Puct.
+    pub(crate) priors: &'a [f64],
+    // Blend in AMAF statistics with this equivalence parameter.
+    pub(crate) rave_equivalence: Option<f64>,
+    // The reward a virtual loss counts as.
+    pub(crate) loss_reward: f64,
+}
+
+// How the move to play is picked from the root's statistics once the search
+// is over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FinalMovePolicy {
+    // The highest mean reward.
+    MaxChild,
+    // The most visits.
+    RobustChild,
+    // The highest lower confidence bound on the mean reward.
+    SecureChild,
+}
+
+// The search statistics of one action at the root. The confidence interval
+// covers all possible rewards for actions that have not been sampled.
+#[derive(Debug, Clone, Copy)]
+pub struct ActionReport {
+    pub action: Action,
+    pub visits: usize,
+    pub mean: f64,
+    pub lower: f64,
+    pub upper: f64,
+}
+
+pub(crate) struct Node {
+    pub(crate) actions: HashMap<Action, Arrow>,
+    pub(crate) total_samples: f64,
+    pub(crate) virtual_losses: f64,
 }
 
 impl Node {
-    fn new() -> Node {
+    pub(crate) fn new() -> Node {
         Node {
             actions: HashMap::new(),
             total_samples: 0.0,
+            virtual_losses: 0.0,
         }
     }
 
-    fn select<R: Rng>(&self, legal_actions: &[Action], exploration: f64, rng: &mut R) -> Action {
+    pub(crate) fn select<R: Rng>(&self, legal_actions: &[Action], selection: &Selection, rng: &mut R) -> Action {
         let mut unexplored_actions = Vec::new();
-        for &a in legal_actions.iter() {
-            if !self.actions.contains_key(&a) {
-                unexplored_actions.push(a);
+        if selection.rule != SelectionRule::Puct {
+            for &a in legal_actions.iter() {
+                // Arrows may exist with AMAF statistics only.
+                let explored = match self.actions.get(&a) {
+                    Some(arrow) => arrow.num_samples + arrow.virtual_losses > 0.0,
+                    None => false,
+                };
+                if !explored {
+                    unexplored_actions.push(a);
+                }
             }
         }
         if unexplored_actions.is_empty() {
             let mut best_actions: Vec<Action> = Vec::new();
-            let mut best_grade: f64 = -1.0;
+            let mut best_grade = f64::NEG_INFINITY;
 
-            for &a in legal_actions.iter() {
-                let arrow = &self.actions[&a];
-                let grade = arrow.expected_reward + exploration * (self.total_samples.ln() / arrow.num_samples).sqrt();
+            let total_samples = self.total_samples + self.virtual_losses;
+            for (i, &a) in legal_actions.iter().enumerate() {
+                let (mean, num_samples, variance) = match self.actions.get(&a) {
+                    Some(arrow) => {
+                        let num_samples = arrow.num_samples + arrow.virtual_losses;
+                        let mean = if num_samples > 0.0 {
+                            (arrow.expected_reward * arrow.num_samples + selection.loss_reward * arrow.virtual_losses) / num_samples
+                        } else {
+                            0.0
+                        };
+                        let mean = arrow.blended_reward(mean, num_samples, selection.rave_equivalence);
+                        (mean, num_samples, arrow.variance())
+                    },
+                    None => (0.0, 0.0, 0.0),
+                };
+                let mean = (mean - selection.reward_offset) / selection.reward_scale;
+                let grade = match selection.rule {
+                    SelectionRule::Ucb1 => {
+                        mean + selection.exploration * (total_samples.ln() / num_samples).sqrt()
+                    },
+                    SelectionRule::Ucb1Tuned => {
+                        let log_ratio = total_samples.ln() / num_samples;
+                        let variance = variance / (selection.reward_scale * selection.reward_scale);
+                        let bound = variance + (2.0 * log_ratio).sqrt();
+                        mean + selection.exploration * (log_ratio * bound.min(0.25)).sqrt()
+                    },
+                    SelectionRule::Puct => {
+                        mean + selection.exploration * selection.priors[i] * total_samples.sqrt() / (1.0 + num_samples)
+                    },
+                };
                 if grade > best_grade {
                     best_actions.clear();
                     best_actions.push(a);
@@ -73,75 +249,892 @@ impl Node {
         }
     }
 
-    fn add_sample(&mut self, action: Action, result: f64) {
+    // The explored legal action with the highest expected reward, breaking
+    // ties randomly.
+    pub(crate) fn best_action<R: Rng>(&self, legal_actions: &[Action], rng: &mut R) -> Action {
+        self.final_action(legal_actions, FinalMovePolicy::MaxChild, 0.0, 0.0, rng)
+    }
+
+    // The explored legal action that the policy rates highest, breaking ties
+    // randomly. Confidence bounds are confidence standard errors wide, for
+    // rewards spanning reward_range. If no legal action has been explored
+    // yet, any of them is as good a guess.
+    pub(crate) fn final_action<R: Rng>(
+        &self,
+        legal_actions: &[Action],
+        policy: FinalMovePolicy,
+        confidence: f64,
+        reward_range: f64,
+        rng: &mut R,
+    ) -> Action {
+        let mut best_actions = Vec::new();
+        let mut best_grade = f64::NEG_INFINITY;
+        for &action in legal_actions.iter() {
+            let arrow = match self.actions.get(&action) {
+                Some(arrow) if arrow.num_samples > 0.0 => arrow,
+                _ => continue,
+            };
+            let grade = arrow.final_grade(policy, confidence, reward_range);
+            if grade > best_grade {
+                best_actions.clear();
+                best_actions.push(action);
+                best_grade = grade;
+            } else if grade == best_grade {
+                best_actions.push(action);
+            }
+        }
+        if best_actions.is_empty() {
+            best_actions = legal_actions.to_vec();
+        }
+        let index = Range::new(0, best_actions.len()).ind_sample(rng);
+        best_actions[index]
+    }
+
+    // Statistics for each of the given actions, most visited first.
+    pub(crate) fn report(&self, legal_actions: &[Action], confidence: f64, objective: Objective) -> Vec<ActionReport> {
+        let mut report: Vec<ActionReport> = legal_actions.iter().map(|&action| {
+            match self.actions.get(&action) {
+                Some(arrow) if arrow.num_samples > 0.0 => {
+                    let radius = arrow.confidence_radius(confidence, objective.reward_range());
+                    ActionReport {
+                        action,
+                        visits: arrow.num_samples as usize,
+                        mean: arrow.expected_reward,
+                        lower: arrow.expected_reward - radius,
+                        upper: arrow.expected_reward + radius,
+                    }
+                },
+                _ => ActionReport {
+                    action,
+                    visits: 0,
+                    mean: 0.0,
+                    lower: objective.min_reward(),
+                    upper: objective.max_reward(),
+                },
+            }
+        }).collect();
+        report.sort_by(|a, b| b.visits.cmp(&a.visits).then(b.mean.partial_cmp(&a.mean).unwrap()));
+        report
+    }
+
+    pub(crate) fn add_virtual_loss(&mut self, action: Action, amount: f64) {
+        let arrow: &mut Arrow = self.actions.entry(action).or_insert_with(Arrow::new);
+        arrow.virtual_losses += amount;
+        self.virtual_losses += amount;
+    }
+
+    pub(crate) fn remove_virtual_loss(&mut self, action: Action, amount: f64) {
+        if let Some(arrow) = self.actions.get_mut(&action) {
+            arrow.virtual_losses -= amount;
+            self.virtual_losses -= amount;
+        }
+    }
+
+    // Records a result in the AMAF statistics of every distinct action in
+    // moves made by the given player.
+    pub(crate) fn add_amaf_samples(&mut self, player: Player, moves: &[(Player, Action)], result: f64) {
+        let mut seen = Vec::new();
+        for &(actor, action) in moves.iter() {
+            if actor != player || seen.contains(&action) {
+                continue;
+            }
+            seen.push(action);
+            self.actions.entry(action).or_insert_with(Arrow::new).add_amaf_sample(result);
+        }
+    }
+
+    pub(crate) fn add_sample(&mut self, action: Action, result: f64) {
         let arrow: &mut Arrow = self.actions.entry(action).or_insert_with(Arrow::new);
         arrow.add_sample(result);
         self.total_samples += 1.0;
     }
 }
 
+// What a playout's result is worth, i.e. what the search maximizes in
+// expectation. Playouts cut off by the evaluator count with the estimated
+// score and as not having struck out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Objective {
+    // The final score.
+    ExpectedScore,
+    // 1 for a perfect game and 0 otherwise, i.e. the probability of scoring
+    // MAX_SCORE.
+    PerfectGame,
+    // A concave utility of the score, MAX_SCORE * (1 - e^(-a s / MAX_SCORE))
+    // / (1 - e^(-a)), for a risk aversion a > 0. Larger values of a care
+    // more about avoiding low scores, and as a goes to 0 it becomes the
+    // score itself.
+    RiskAverse(f64),
+    // The final score, minus the given penalty for losing all three lives.
+    StrikeoutPenalty(f64),
+}
+
+impl Objective {
+    pub fn reward(&self, score: f64, struck_out: bool) -> f64 {
+        match *self {
+            Objective::ExpectedScore => score,
+            Objective::PerfectGame => if score >= MAX_SCORE { 1.0 } else { 0.0 },
+            Objective::RiskAverse(0.0) => score,
+            Objective::RiskAverse(a) => MAX_SCORE * (1.0 - (-a * score / MAX_SCORE).exp()) / (1.0 - (-a).exp()),
+            Objective::StrikeoutPenalty(penalty) => if struck_out { score - penalty } else { score },
+        }
+    }
+
+    pub fn min_reward(&self) -> f64 {
+        match *self {
+            Objective::StrikeoutPenalty(penalty) => -penalty,
+            _ => 0.0,
+        }
+    }
+
+    pub fn max_reward(&self) -> f64 {
+        match *self {
+            Objective::PerfectGame => 1.0,
+            _ => MAX_SCORE,
+        }
+    }
+
+    pub fn reward_range(&self) -> f64 {
+        self.max_reward() - self.min_reward()
+    }
+}
+
+// Progressive widening: a node that has been sampled n times only considers
+// the max(1, floor(coefficient * n^exponent)) legal actions with the highest
+// prior, so new actions are admitted gradually instead of every action
+// having to be tried once first.
+#[derive(Debug, Clone, Copy)]
+pub struct Widening {
+    pub coefficient: f64,
+    pub exponent: f64,
+}
+
+impl Default for Widening {
+    fn default() -> Widening {
+        Widening {
+            coefficient: 2.0,
+            exponent: 0.5,
+        }
+    }
+}
+
+impl Widening {
+    pub fn width(&self, samples: f64) -> usize {
+        let width = (self.coefficient * samples.powf(self.exponent)).floor();
+        if width < 1.0 {
+            1
+        } else {
+            width as usize
+        }
+    }
+}
+
+// How the hidden cards are sampled during a playout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SearchMode {
+    // Redeterminize from the acting player's view before every action.
+    PerStep,
+    // Determinize once at the root and play the whole playout in that world
+    // (perfect information Monte Carlo).
+    PerPlayout,
+}
+
+#[derive(Debug, Clone)]
+pub struct MctsConfig {
+    pub exploration: f64,
+    pub selection_rule: SelectionRule,
+    // Divide scores by the maximum score during selection, so that rewards
+    // lie in [0, 1] as the selection formulas assume.
+    pub normalize_rewards: bool,
+    // Priors for SelectionRule::Puct, and the order in which progressive
+    // widening admits actions.
+    pub prior: Arc<dyn ActionPrior>,
+    pub widening: Option<Widening>,
+    // Leave out plays and discards the actor knows from clues to be
+    // mistakes. See CompactState::is_dominated.
+    pub prune_dominated: bool,
+    // Search only one clue from each set of clues to the same player that
+    // leave them knowing the same. See GameState::action_classes.
+    pub merge_clues: bool,
+    pub objective: Objective,
+    // If set, selection blends each action's statistics with its
+    // all-moves-as-first statistics (RAVE), using this equivalence
+    // parameter: roughly the number of real samples at which both get equal
+    // weight.
+    pub rave_equivalence: Option<f64>,
+    pub final_move: FinalMovePolicy,
+    // The number of standard errors to either side of the mean that
+    // confidence intervals span, for SecureChild and the root report.
+    pub confidence: f64,
+    pub search_mode: SearchMode,
+    // The number of samples at the objective's lowest reward that a playout
+    // provisionally adds to each arrow it selects until its result is known.
+    // Zero disables virtual loss.
+    pub virtual_loss: f64,
+    // Chooses the moves once a playout has left the tree.
+    pub rollout: Arc<dyn RolloutPolicy>,
+    // The most moves a rollout plays before the game is cut off and scored
+    // by the evaluator. None plays every rollout to the end of the game.
+    pub rollout_depth: Option<usize>,
+    pub evaluator: Arc<dyn Evaluator>,
+    // If set, search solves the root exactly instead of running playouts
+    // once the endgame is within these limits and the time limit allows,
+    // with every player deciding from their own view.
+    pub endgame: Option<EndgameConfig>,
+}
+
+impl Default for MctsConfig {
+    fn default() -> MctsConfig {
+        MctsConfig {
+            exploration: 1.4,
+            selection_rule: SelectionRule::Ucb1,
+            normalize_rewards: true,
+            prior: Arc::new(HeuristicPrior::default()),
+            widening: None,
+            prune_dominated: false,
+            merge_clues: false,
+            objective: Objective::ExpectedScore,
+            rave_equivalence: None,
+            final_move: FinalMovePolicy::RobustChild,
+            confidence: 1.96,
+            search_mode: SearchMode::PerStep,
+            virtual_loss: 1.0,
+            rollout: Arc::new(UniformRollout),
+            rollout_depth: None,
+            evaluator: Arc::new(PaceEvaluator::default()),
+            endgame: Some(EndgameConfig::default()),
+        }
+    }
+}
+
+// Limits on how much effort a call to MctsState::search may spend. Search
+// stops as soon as any of the limits is reached, so at least one of
+// time_limit and max_playouts must be set.
+#[derive(Debug, Clone)]
+pub struct SearchBudget {
+    pub time_limit: Option<Duration>,
+    pub max_playouts: Option<usize>,
+    // If set, also stop once the best root action can no longer be
+    // overtaken: either because the remaining playouts could not change the
+    // ranking, or because its Hoeffding bound at this failure probability
+    // separates it from every other action.
+    pub early_stop: Option<f64>,
+    // How many playouts to run between checks of the early stopping rule.
+    pub check_interval: usize,
+}
+
+impl Default for SearchBudget {
+    fn default() -> SearchBudget {
+        SearchBudget {
+            time_limit: None,
+            max_playouts: None,
+            early_stop: None,
+            check_interval: 100,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    TimeLimit,
+    PlayoutLimit,
+    Decided,
+    // The endgame solver found the best action without playouts.
+    Solved,
+}
+
+#[derive(Debug, Clone)]
+pub struct SearchReport {
+    pub playouts: usize,
+    pub elapsed: Duration,
+    pub stop_reason: StopReason,
+}
+
+// What every player learns from an action: the action itself, the card that
+// was played or discarded, and the slots a clue touched. Children are keyed
+// on this alone, so a node never splits on cards the player to act there
+// cannot see. Drawn cards are left out: the player to act sees partners'
+// draws, but keying on them would split the drawer's own later nodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Edge {
+    action: Action,
+    revealed: Option<Card>,
+    // The slots of the target's hand that a clue touched, as a bit mask.
+    touched: u8,
+}
+
+impl Edge {
+    // The edge for an action, given a state before or after it, which has
+    // the same hands wherever a clue could have touched.
+    fn new(completed_action: &CompletedAction, state: &GameState) -> Edge {
+        let (revealed, touched) = match *completed_action {
+            CompletedAction::Discarded(_, c) | CompletedAction::Played(_, c) => (Some(c), 0),
+            CompletedAction::Clued(target, _, ref matching) => {
+                let mut touched = 0;
+                for (slot, c_id) in state.hand(target).iter().enumerate() {
+                    if matching.contains(c_id) {
+                        touched |= 1 << slot;
+                    }
+                }
+                (None, touched)
+            },
+        };
+        Edge {
+            action: completed_action.action(),
+            revealed,
+            touched,
+        }
+    }
+}
+
+// Nodes live in an arena and refer to their children by index, so
+// descending the tree only compares edges. Each node stands for the public
+// history along its path, which every player to act there can observe.
+struct TreeNode {
+    stats: Node,
+    children: Vec<(Edge, usize)>,
+}
+
+impl TreeNode {
+    fn new() -> TreeNode {
+        TreeNode {
+            stats: Node::new(),
+            children: Vec::new(),
+        }
+    }
+
+    fn child(&self, edge: &Edge) -> Option<usize> {
+        self.children.iter().find(|&&(e, _)| e == *edge).map(|&(_, index)| index)
+    }
+}
+
+const ROOT: usize = 0;
+
+// The first chunk of the arena holds this many nodes, and each later chunk
+// twice as many as the one before.
+const FIRST_CHUNK: usize = 64;
+const CHUNKS: usize = 40;
+
+// An append-only arena of tree nodes, stored in chunks that are allocated
+// as they are needed and never move. Adding a node reserves an index with
+// an atomic counter, so threads expanding different parts of the tree
+// never wait on each other; only the nodes themselves are locked.
+struct Arena {
+    chunks: Vec<OnceLock<Box<[Mutex<TreeNode>]>>>,
+    len: AtomicUsize,
+}
+
+impl Arena {
+    // An arena holding only an empty root.
+    fn new() -> Arena {
+        let arena = Arena {
+            chunks: (0..CHUNKS).map(|_| OnceLock::new()).collect(),
+            len: AtomicUsize::new(0),
+        };
+        arena.push(TreeNode::new());
+        arena
+    }
+
+    // The chunk an index falls in, and its offset there.
+    fn locate(index: usize) -> (usize, usize) {
+        let chunk = (index / FIRST_CHUNK + 1).ilog2() as usize;
+        (chunk, index - FIRST_CHUNK * ((1 << chunk) - 1))
+    }
+
+    fn len(&self) -> usize {
+        self.len.load(Ordering::SeqCst)
+    }
+
+    // Only indices returned by push may be looked up, and only once the
+    // push has returned.
+    fn get(&self, index: usize) -> &Mutex<TreeNode> {
+        let (chunk, offset) = Arena::locate(index);
+        &self.chunks[chunk].get().expect("Node index out of range")[offset]
+    }
+
+    fn get_mut(&mut self, index: usize) -> &mut TreeNode {
+        let (chunk, offset) = Arena::locate(index);
+        self.chunks[chunk].get_mut().expect("Node index out of range")[offset].get_mut().unwrap()
+    }
+
+    fn push(&self, node: TreeNode) -> usize {
+        let index = self.len.fetch_add(1, Ordering::SeqCst);
+        let (chunk, offset) = Arena::locate(index);
+        let nodes = self.chunks[chunk].get_or_init(|| {
+            (0..FIRST_CHUNK << chunk).map(|_| Mutex::new(TreeNode::new())).collect()
+        });
+        *nodes[offset].lock().unwrap() = node;
+        index
+    }
+}
+
+// Which cards each player holds at a node, followed down the tree from the
+// root by their ids, so that edges can be checked against a view.
+#[derive(Clone)]
+struct Deal {
+    player: Player,
+    hands: Vec<Vec<CardId>>,
+    next_card_id: CardId,
+    deck_size: usize,
+}
+
+impl Deal {
+    fn new(state: &GameState) -> Deal {
+        Deal {
+            player: state.current_player(),
+            hands: PLAYERS.iter().map(|&p| state.hand(p).to_vec()).collect(),
+            next_card_id: state.next_card_id,
+            deck_size: state.deck_size,
+        }
+    }
+
+    // Moves along an edge, returning false if it contradicts a card that the
+    // view knows.
+    fn follow(&mut self, edge: &Edge, view: &GameState) -> bool {
+        let hand = &mut self.hands[self.player.index()];
+        match edge.action {
+            Action::Play(i) | Action::Discard(i) => {
+                let c_id = hand.remove(i);
+                if view.known_card(c_id).is_some_and(|c| Some(c) != edge.revealed) {
+                    return false;
+                }
+                if self.deck_size > 0 {
+                    hand.push(self.next_card_id);
+                    self.next_card_id.increment();
+                    self.deck_size -= 1;
+                }
+            },
+            Action::Clue(target, clue) => {
+                for (slot, &c_id) in self.hands[target.index()].iter().enumerate() {
+                    let touched = edge.touched & (1 << slot) != 0;
+                    if view.known_card(c_id).is_some_and(|c| clue.matches(c) != touched) {
+                        return false;
+                    }
+                }
+            },
+        }
+        self.player = self.player.next();
+        true
+    }
+}
+
+// One step of a playout inside the tree: the node, the action taken, and
+// whether a virtual loss was added that the update must take back.
+struct PlayoutStep {
+    node: usize,
+    action: Action,
+    virtual_loss: bool,
+}
+
+// The record of a playout: the steps taken inside the tree, and the node to
+// add where the playout left it, given by its parent, edge and action. When
+// RAVE is enabled, the trace holds every move of the playout with the player
+// who made it; the steps and expansion are its first moves, in order.
+pub struct MctsUpdates {
+    steps: Vec<PlayoutStep>,
+    expansion: Option<(usize, Edge, Action)>,
+    trace: Vec<(Player, Action)>,
+}
+
+// Playouts lock the individual nodes they touch, one at a time, except that
+// adding a node briefly holds its parent's lock.
 pub struct MctsState {
     root: GameState,
-    exploration: f64,
-    nodes: HashMap<u64, Node>,
+    config: MctsConfig,
+    nodes: Arena,
+    // The root's solution, once the endgame solver has found it.
+    solution: Option<EndgameSolution>,
 }
 
 impl MctsState {
     pub fn new(root: GameState, exploration: f64) -> MctsState {
+        MctsState::with_config(root, MctsConfig {
+            exploration,
+            ..MctsConfig::default()
+        })
+    }
+
+    pub fn with_config(root: GameState, config: MctsConfig) -> MctsState {
         MctsState {
             root,
-            exploration,
-            nodes: HashMap::new(),
+            config,
+            nodes: Arena::new(),
+            solution: None,
+        }
+    }
+
+    // Moves the root forward by an action taken in the real game, given the
+    // view of the player who acts next. The subtree below that action is
+    // kept, except for branches in which a card the new root player can see
+    // was played, discarded or clued as something it is not. All other nodes
+    // are dropped.
+    pub fn advance(&mut self, action: &CompletedAction, new_root: GameState) {
+        let edge = Edge::new(action, &new_root);
+        let nodes = &mut self.nodes;
+        let new_root_index = nodes.get_mut(ROOT).child(&edge);
+
+        let mut kept = Arena::new();
+        if let Some(index) = new_root_index {
+            // Move the subtree into a fresh arena, renumbering as we go.
+            let mut queue = vec![(index, ROOT, Deal::new(&new_root))];
+            while let Some((old_index, new_index, deal)) = queue.pop() {
+                let mut node = mem::replace(nodes.get_mut(old_index), TreeNode::new());
+                let children = mem::take(&mut node.children);
+                for (edge, child) in children {
+                    let mut child_deal = deal.clone();
+                    if child_deal.follow(&edge, &new_root) {
+                        let child_index = kept.push(TreeNode::new());
+                        queue.push((child, child_index, child_deal));
+                        node.children.push((edge, child_index));
+                    }
+                }
+                *kept.get_mut(new_index) = node;
+            }
+        }
+        *nodes = kept;
+        self.root = new_root;
+        self.solution = None;
+    }
+
+    pub fn num_nodes(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub(crate) fn with_root_node<T, F>(&self, f: F) -> T
+        where
+        F: FnOnce(&Node) -> T,
+    {
+        let root = self.nodes.get(ROOT).lock().unwrap();
+        f(&root.stats)
+    }
+
+    // Calls f with the statistics of every node in the tree.
+    #[cfg(test)]
+    pub(crate) fn for_each_node<F: FnMut(&Node)>(&self, mut f: F) {
+        for index in 0..self.nodes.len() {
+            f(&self.nodes.get(index).lock().unwrap().stats);
         }
     }
 
+    // The endgame solver's solution for the root, if the last search solved
+    // it.
+    pub fn endgame_solution(&self) -> Option<EndgameSolution> {
+        self.solution
+    }
+
     pub fn choose_action<R: Rng>(&self, rng: &mut R) -> Action {
-        let mut best_actions = Vec::new();
-        let mut best_expectation = -1.0;
-        let root_fingerprint = self.root.fingerprint();
-        let mut hasher = DefaultHasher::new();
-        root_fingerprint.hash(&mut hasher);
-        let hash = hasher.finish();
-        let root_node = self.nodes.get(&hash).unwrap();
-        for (&action, arrow) in root_node.actions.iter() {
-            if arrow.expected_reward > best_expectation {
-                best_actions.clear();
-                best_actions.push(action);
-                best_expectation = arrow.expected_reward;
-            } else if arrow.expected_reward == best_expectation {
-                best_actions.push(action);
+        if let Some(solution) = self.solution {
+            return solution.action;
+        }
+        let (legal_actions, _) = self.candidates(&self.root);
+        let range = self.config.objective.reward_range();
+        self.with_root_node(|node| node.final_action(&legal_actions, self.config.final_move, self.config.confidence, range, rng))
+    }
+
+    // The search statistics of every action choose_action could pick at the
+    // root.
+    pub fn root_report(&self) -> Vec<ActionReport> {
+        let (legal_actions, _) = self.candidates(&self.root);
+        self.with_root_node(|node| node.report(&legal_actions, self.config.confidence, self.config.objective))
+    }
+
+    // Runs playouts on every rayon thread until the budget is exhausted.
+    // Each thread updates the shared tree as soon as its playout finishes. If
+    // a particle filter is given, each playout starts from one of its
+    // particles. If the endgame solver can solve the root, it runs instead
+    // and no playouts are needed.
+    pub fn search(&mut self, budget: &SearchBudget, filter: Option<&ParticleFilter>) -> SearchReport {
+        assert!(budget.time_limit.is_some() || budget.max_playouts.is_some(),
+            "Search budget needs a time limit or a playout limit");
+
+        let start = Instant::now();
+        if self.solution.is_none() {
+            if let Some(ref endgame) = self.config.endgame {
+                let mut solver = EndgameSolver::new(endgame.clone(), self.config.objective);
+                if let Some(limit) = budget.time_limit {
+                    solver.set_deadline(start + limit);
+                }
+                self.solution = solver.solve(&self.root);
             }
         }
-        let index = Range::new(0, best_actions.len()).ind_sample(rng);
-        best_actions[index]
+        if self.solution.is_some() {
+            return SearchReport {
+                playouts: 0,
+                elapsed: start.elapsed(),
+                stop_reason: StopReason::Solved,
+            };
+        }
+
+        let started = AtomicUsize::new(0);
+        let completed = AtomicUsize::new(0);
+        let stopped = AtomicBool::new(false);
+        let stop_reason: Mutex<Option<StopReason>> = Mutex::new(None);
+        let stop = |reason: StopReason| {
+            let mut stop_reason = stop_reason.lock().unwrap();
+            if stop_reason.is_none() {
+                *stop_reason = Some(reason);
+            }
+            stopped.store(true, Ordering::SeqCst);
+        };
+
+        let mcts = &*self;
+        (0..rayon::current_num_threads()).into_par_iter().for_each(|_| {
+            let mut rng = rand::thread_rng();
+            while !stopped.load(Ordering::SeqCst) {
+                if let Some(limit) = budget.time_limit {
+                    if start.elapsed() >= limit {
+                        stop(StopReason::TimeLimit);
+                        break;
+                    }
+                }
+                let index = started.fetch_add(1, Ordering::SeqCst);
+                if let Some(max) = budget.max_playouts {
+                    if index >= max {
+                        stop(StopReason::PlayoutLimit);
+                        break;
+                    }
+                }
+                if let Some(delta) = budget.early_stop {
+                    if index.is_multiple_of(budget.check_interval) {
+                        let done = completed.load(Ordering::SeqCst);
+                        let remaining = budget.max_playouts.map(|max| max - done);
+                        if mcts.root_decided(remaining, delta) {
+                            stop(StopReason::Decided);
+                            break;
+                        }
+                    }
+                }
+
+                let (updates, result) = match filter {
+                    Some(filter) => {
+                        let world = filter.sample(&mut rng);
+                        mcts.run_playout_from(world, &mut rng)
+                    },
+                    None => mcts.run_playout(&mut rng),
+                };
+                mcts.update(updates, result);
+                completed.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+
+        SearchReport {
+            playouts: completed.into_inner(),
+            elapsed: start.elapsed(),
+            stop_reason: stop_reason.into_inner().unwrap().unwrap(),
+        }
     }
 
-    pub fn run_playout<R: Rng>(&self, rng: &mut R) -> (Vec<(u64, Action)>, f64) {
-        let mut updates: Vec<(u64, Action)> = Vec::new();
-        let mut current_state = self.root.clone();
+    // Whether the action choose_action would pick can no longer be
+    // overtaken by any other legal action. With progressive widening, only
+    // the actions the root will have admitted by the end of the search
+    // compete.
+    fn root_decided(&self, remaining: Option<usize>, delta: f64) -> bool {
+        let (mut legal_actions, mut priors) = self.candidates(&self.root);
+        let root_samples = self.with_root_node(|root_node| root_node.total_samples);
+        self.widen(&mut legal_actions, &mut priors, root_samples + remaining.unwrap_or(0) as f64);
+        let range = self.config.objective.reward_range();
+        let policy = self.config.final_move;
+        let stats: Vec<(f64, f64, f64)> = self.with_root_node(|root_node| {
+            legal_actions.iter()
+                .map(|a| match root_node.actions.get(a) {
+                    Some(arrow) if arrow.num_samples > 0.0 => {
+                        (arrow.expected_reward, arrow.num_samples, arrow.final_grade(policy, self.config.confidence, range))
+                    },
+                    _ => (0.0, 0.0, f64::NEG_INFINITY),
+                })
+                .collect()
+        });
+        if stats.iter().all(|&(_, samples, _)| samples == 0.0) {
+            return false;
+        }
+        if stats.len() < 2 {
+            return true;
+        }
+
+        let mut best = 0;
+        for i in 1..stats.len() {
+            if stats[i].2 > stats[best].2 {
+                best = i;
+            }
+        }
+        let (best_mean, best_samples, _) = stats[best];
+        let others = || stats.iter().enumerate().filter(|&(i, _)| i != best).map(|(_, &(mean, samples, _))| (mean, samples));
+
+        // The most visited action keeps its lead if no other action could
+        // catch up even with every remaining playout.
+        if let Some(remaining) = remaining {
+            if policy == FinalMovePolicy::RobustChild && others().all(|(_, samples)| samples + (remaining as f64) < best_samples) {
+                return true;
+            }
+        }
+
+        // Even if every remaining playout went to another action and scored
+        // perfectly, while the best action's estimate stayed put or fell,
+        // the ranking would not change.
+        if let Some(remaining) = remaining {
+            let remaining = remaining as f64;
+            let objective = self.config.objective;
+            let best_lower = (best_mean * best_samples + objective.min_reward() * remaining) / (best_samples + remaining);
+            if others().all(|(mean, samples)| {
+                samples + remaining > 0.0 && (mean * samples + objective.max_reward() * remaining) / (samples + remaining) < best_lower
+            }) {
+                return true;
+            }
+        }
+
+        let range = self.config.objective.reward_range();
+        let radius = |samples: f64| range * ((2.0 / delta).ln() / (2.0 * samples)).sqrt();
+        if best_samples == 0.0 || others().any(|(_, samples)| samples == 0.0) {
+            return false;
+        }
+        let best_lower = best_mean - radius(best_samples);
+        others().all(|(mean, samples)| mean + radius(samples) < best_lower)
+    }
+
+    // The actions search considers in a state, with their priors if the
+    // selection rule or widening needs them.
+    fn candidates(&self, state: &GameState) -> (Vec<Action>, Vec<f64>) {
+        let mut legal_actions = state.legal_actions_with(self.config.merge_clues);
+        if self.config.prune_dominated {
+            let compact = CompactState::from(state);
+            let kept: Vec<Action> = legal_actions.iter().cloned().filter(|&a| !compact.is_dominated(a)).collect();
+            if !kept.is_empty() {
+                legal_actions = kept;
+            }
+        }
+        let priors = if self.config.selection_rule == SelectionRule::Puct || self.config.widening.is_some() {
+            self.config.prior.priors(state, &legal_actions)
+        } else {
+            Vec::new()
+        };
+        (legal_actions, priors)
+    }
+
+    // Keeps only the actions progressive widening admits at a node that has
+    // been sampled the given number of times. The priors of the admitted
+    // actions are scaled back up to sum to one.
+    fn widen(&self, legal_actions: &mut Vec<Action>, priors: &mut Vec<f64>, samples: f64) {
+        if let Some(widening) = self.config.widening {
+            let width = widening.width(samples);
+            if width < legal_actions.len() {
+                // Stable, so ties keep the legal_actions order.
+                let mut order: Vec<usize> = (0..legal_actions.len()).collect();
+                order.sort_by(|&a, &b| priors[b].partial_cmp(&priors[a]).unwrap());
+                order.truncate(width);
+                *legal_actions = order.iter().map(|&i| legal_actions[i]).collect();
+                *priors = order.iter().map(|&i| priors[i]).collect();
+                let total: f64 = priors.iter().sum();
+                if total > 0.0 {
+                    for prior in priors.iter_mut() {
+                        *prior /= total;
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn run_playout<R: Rng>(&self, rng: &mut R) -> (MctsUpdates, f64) {
+        let mut world = self.root.clone();
+        world.determinize(&HashMap::new(), rng);
+        self.playout(world, false, rng)
+    }
+
+    // Runs a playout from a caller-supplied determinization of the root,
+    // such as a particle drawn from a ParticleFilter. In PerPlayout mode this
+    // world is kept until the end of the game. In PerStep mode the root
+    // player's hand is kept from it for the whole playout, since it is a
+    // better sample of their belief than a uniform redraw, and only the other
+    // players' hidden cards are redrawn on their turns. Once the playout
+    // leaves the tree, the rest of the game is played out in the current
+    // world by the configured rollout policy, up to the configured rollout
+    // depth.
+    pub fn run_playout_from<R: Rng>(&self, world: GameState, rng: &mut R) -> (MctsUpdates, f64) {
+        self.playout(world, true, rng)
+    }
+
+    fn playout<R: Rng>(&self, world: GameState, keep_root_hand: bool, rng: &mut R) -> (MctsUpdates, f64) {
+        let root_player = self.root.current_player();
+        let mut updates = MctsUpdates {
+            steps: Vec::new(),
+            expansion: None,
+            trace: Vec::new(),
+        };
+        let record_trace = self.config.rave_equivalence.is_some();
+        let mut current_state = world;
+        let mut current_node = ROOT;
         let result = loop {
-            let fingerprint = current_state.fingerprint();
-            let mut hasher = DefaultHasher::new();
-            fingerprint.hash(&mut hasher);
-            let hash = hasher.finish();
-            let action = match self.nodes.get(&hash) {
-                Some(node) => {
-                    node.select(&current_state.legal_actions(), self.exploration, rng)
-                },
-                None => {
-                    let legal_actions = current_state.legal_actions();
-                    let index = Range::new(0, legal_actions.len()).ind_sample(rng);
-                    legal_actions[index]
-                },
+            let (mut legal_actions, mut priors) = self.candidates(&current_state);
+            let action = {
+                let mut node = self.nodes.get(current_node).lock().unwrap();
+                self.widen(&mut legal_actions, &mut priors, node.stats.total_samples);
+                let selection = Selection {
+                    rule: self.config.selection_rule,
+                    exploration: self.config.exploration,
+                    reward_offset: if self.config.normalize_rewards { self.config.objective.min_reward() } else { 0.0 },
+                    reward_scale: if self.config.normalize_rewards { self.config.objective.reward_range() } else { 1.0 },
+                    priors: &priors,
+                    rave_equivalence: self.config.rave_equivalence,
+                    loss_reward: self.config.objective.min_reward(),
+                };
+                let action = node.stats.select(&legal_actions, &selection, rng);
+                let virtual_loss = self.config.virtual_loss > 0.0;
+                if virtual_loss {
+                    node.stats.add_virtual_loss(action, self.config.virtual_loss);
+                }
+                updates.steps.push(PlayoutStep {
+                    node: current_node,
+                    action,
+                    virtual_loss,
+                });
+                action
             };
 
-            updates.push((hash, action));
-            
-            current_state.determinize(&HashMap::new(), rng);
-
+            let actor = current_state.current_player();
+            if record_trace {
+                updates.trace.push((actor, action));
+            }
             match current_state.act(action) {
-                ActionResult::Acted(_) => {
-                    current_state.reduce_to_current_view();
+                ActionResult::Acted(completed_action) => {
+                    let edge = Edge::new(&completed_action, &current_state);
+                    let child = self.nodes.get(current_node).lock().unwrap().child(&edge);
+                    match child {
+                        Some(index) => {
+                            current_node = index;
+                            // The other players can see the root player's
+                            // hand, so their redraws leave it alone and
+                            // skipping the root player's own keeps it.
+                            let redraw = !(keep_root_hand && current_state.current_player() == root_player);
+                            if self.config.search_mode == SearchMode::PerStep && redraw {
+                                current_state.reduce_to_current_view();
+                                current_state.determinize(&HashMap::new(), rng);
+                            }
+                        },
+                        None => {
+                            // Leaving the tree: finish the game in the
+                            // compact representation, which is much cheaper
+                            // to simulate.
+                            let mut compact = CompactState::from(&current_state);
+                            let actions = compact.legal_actions();
+                            let action = self.config.rollout.choose_action(&compact, &actions, rng);
+                            updates.expansion = Some((current_node, edge, action));
+                            if record_trace {
+                                updates.trace.push((compact.current_player(), action));
+                            }
+                            break match compact.act(action) {
+                                CompactResult::Acted => {
+                                    let depth = self.config.rollout_depth.map(|depth| depth.saturating_sub(1));
+                                    let trace = if record_trace { Some(&mut updates.trace) } else { None };
+                                    match rollout(&mut compact, &*self.config.rollout, depth, trace, rng) {
+                                        Some(score) => self.config.objective.reward(score as f64, compact.strikes() == 3),
+                                        None => self.config.objective.reward(self.config.evaluator.evaluate(&compact), false),
+                                    }
+                                },
+                                CompactResult::Finished(score) => self.config.objective.reward(score as f64, compact.strikes() == 3),
+                                CompactResult::Illegal(_) => panic!("MCTS tried to play an illegal action!"),
+                                CompactResult::Error(_) => panic!("MCTS encountered an action error!"),
+                            };
+                        },
+                    }
                 },
                 ActionResult::Illegal(_) => {
                     panic!("MCTS tried to play an illegal action!");
@@ -150,7 +1143,7 @@ impl MctsState {
                     panic!("MCTS encountered an action error!");
                 },
                 ActionResult::Finished(score) => {
-                    break (score as f64);
+                    break self.config.objective.reward(score as f64, current_state.strikes == 3);
                 },
             }
         };
@@ -158,10 +1151,41 @@ impl MctsState {
         (updates, result)
     }
 
-    pub fn update(&mut self, updates: Vec<(u64, Action)>, result: f64) {
-        for (hash, action) in updates {
-            let node = self.nodes.entry(hash).or_insert_with(Node::new);
-            node.add_sample(action, result);
+    // Records the result of a playout. Every playout must eventually be
+    // passed here, or the virtual losses it added will never be removed.
+    pub fn update(&self, updates: MctsUpdates, result: f64) {
+        if let Some((parent, edge, action)) = updates.expansion {
+            let index = {
+                let mut parent = self.nodes.get(parent).lock().unwrap();
+                // Another thread may have added the same node in the
+                // meantime.
+                match parent.child(&edge) {
+                    Some(index) => index,
+                    None => {
+                        let index = self.nodes.push(TreeNode::new());
+                        parent.children.push((edge, index));
+                        index
+                    },
+                }
+            };
+            let mut node = self.nodes.get(index).lock().unwrap();
+            let stats = &mut node.stats;
+            stats.add_sample(action, result);
+            let depth = updates.steps.len();
+            if let Some(&(player, _)) = updates.trace.get(depth) {
+                stats.add_amaf_samples(player, &updates.trace[depth..], result);
+            }
+        }
+
+        for (depth, step) in updates.steps.into_iter().enumerate() {
+            let mut node = self.nodes.get(step.node).lock().unwrap();
+            if step.virtual_loss {
+                node.stats.remove_virtual_loss(step.action, self.config.virtual_loss);
+            }
+            node.stats.add_sample(step.action, result);
+            if let Some(&(player, _)) = updates.trace.get(depth) {
+                node.stats.add_amaf_samples(player, &updates.trace[depth..], result);
+            }
         }
     }
 }
@@ -2,17 +2,355 @@ extern crate hanabi_ai;
 extern crate rand;
 extern crate rayon;
 
-use hanabi_ai::hanabi::{deck_distribution, Action, ActionResult, Card, GameState};
-use hanabi_ai::basic_mcts::MctsState;
+use hanabi_ai::agent::{Agent, HeuristicAgent};
+use hanabi_ai::conventions::ConventionAgent;
+use hanabi_ai::expectimax::ExpectimaxAgent;
+use hanabi_ai::hat::HatAgent;
+use hanabi_ai::hanabi::{deck_distribution, Action, ActionResult, Card, GameState, Player};
+use hanabi_ai::basic_mcts::{FinalMovePolicy, MctsConfig, MctsState, Objective};
+use hanabi_ai::basic_mcts::{SearchBudget, SearchMode, SelectionRule, Widening};
+use hanabi_ai::ismcts::{IsmctsState, IsmctsUpdates};
+use hanabi_ai::particle_filter::{ActionLikelihood, BlueprintLikelihood, ParticleFilter, UniformLikelihood};
+use hanabi_ai::rollout::{HeuristicRollout, UniformRollout};
+use hanabi_ai::solver::{score_bound, Constraint, OmniscientSolver};
+use hanabi_ai::sparta::SpartaAgent;
 
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rand::{Rng, SeedableRng, StdRng};
 use rand::distributions::{IndependentSample, Range};
 
 use rayon::prelude::*;
 
+#[derive(PartialEq, Eq)]
+enum Algorithm {
+    Basic,
+    Ismcts,
+    Heuristic,
+    Sparta,
+    Expectimax,
+    Conventions,
+    Hat,
+}
+
+impl Algorithm {
+    fn name(&self) -> &'static str {
+        match *self {
+            Algorithm::Basic => "basic",
+            Algorithm::Ismcts => "ismcts",
+            Algorithm::Heuristic => "heuristic",
+            Algorithm::Sparta => "sparta",
+            Algorithm::Expectimax => "expectimax",
+            Algorithm::Conventions => "conventions",
+            Algorithm::Hat => "hat",
+        }
+    }
+}
+
+// Whether a command line flag has any effect with the given algorithm.
+// Values of flags that take one never start with --, so they all pass.
+fn applies(flag: &str, algorithm: &Algorithm) -> bool {
+    match flag {
+        // Only basic MCTS has a configurable search, evaluator and endgame
+        // solver, or keeps a tree between turns.
+        "--mode" | "--rollout" | "--selection" | "--widening" | "--prune" | "--no-endgame" | "--merge-clues"
+            | "--rave" | "--raw-rewards" | "--objective" | "--final-move" | "--report" | "--rollout-depth"
+            | "--early-stop" | "--reuse-tree" => *algorithm == Algorithm::Basic,
+        "--canonical" => *algorithm == Algorithm::Ismcts,
+        // The search budget and the particle filters only feed the tree
+        // searches.
+        "--exploration" | "--playouts" | "--time" | "--particles" | "--infer" => {
+            *algorithm == Algorithm::Basic || *algorithm == Algorithm::Ismcts
+        },
+        "--samples" => *algorithm == Algorithm::Sparta || *algorithm == Algorithm::Expectimax,
+        "--depth" => *algorithm == Algorithm::Expectimax,
+        _ => true,
+    }
+}
+
+// Runs batches of ISMCTS playouts until either limit of the budget is
+// reached.
+fn ismcts_action<R: Rng>(
+    view: GameState,
+    filter: Option<&ParticleFilter>,
+    exploration: f64,
+    canonicalize: bool,
+    budget: &SearchBudget,
+    rng: &mut R,
+) -> Action {
+    let batch_size = 100;
+    let start = Instant::now();
+    let mut batch_updates: Vec<(IsmctsUpdates, f64)> = Vec::new();
+    let mut mcts = IsmctsState::new(view, exploration);
+    mcts.set_canonicalize(canonicalize);
+    let mut playouts = 0;
+    loop {
+        if budget.max_playouts.is_some_and(|max| playouts >= max)
+            || budget.time_limit.is_some_and(|limit| start.elapsed() >= limit) {
+            break;
+        }
+        playouts += batch_size;
+        (0..batch_size).into_par_iter()
+            .map(|_| {
+                let mut rng = rand::thread_rng();
+                match filter {
+                    Some(filter) => {
+                        let world = filter.sample(&mut rng);
+                        mcts.run_playout_from(world, &mut rng)
+                    },
+                    None => mcts.run_playout(&mut rng),
+                }
+            })
+            .collect_into_vec(&mut batch_updates);
+        for (updates, result) in batch_updates.drain(..) {
+            mcts.update(updates, result);
+        }
+    }
+
+    mcts.choose_action(rng)
+}
+
 fn main() {
-    let batch_size: usize = 100;
-    let batches_per_move: usize = 5000;
-    let mut rng = rand::thread_rng();
+
+    // With --particles N, each player keeps a particle filter of N deals and
+    // playouts start from one of its particles instead of a fresh
+    // determinization. --infer also reweights the particles by whether the
+    // heuristic blueprint would have taken each observed action.
+    let mut num_particles: usize = 0;
+    let mut infer = false;
+    // --algorithm selects between basic (the default), ismcts, heuristic
+    // (the rule-based blueprint on its own), sparta (search on top of the
+    // blueprint), expectimax (a shallow search, --depth N moves deep),
+    // conventions (a bot following standard clue conventions) and hat (the
+    // hat-guessing information strategy).
+    // --samples N sets how many deals sparta and expectimax sample per
+    // decision.
+    // --canonical makes ismcts share nodes between suit-symmetric
+    // information sets.
+    let mut canonicalize = false;
+    let mut algorithm = Algorithm::Basic;
+    let mut samples: Option<usize> = None;
+    let mut depth: Option<usize> = None;
+    // --mode per-step|per-playout selects how basic MCTS determinizes, and
+    // --rollout uniform|heuristic how it plays out moves below the tree.
+    // --selection ucb1|ucb1-tuned|puct picks the selection formula (puct
+    // uses the heuristic prior), --exploration C sets its constant, and
+    // --raw-rewards turns off scaling scores into [0, 1]. --rave K blends in
+    // all-moves-as-first statistics with equivalence parameter K.
+    // --widening turns on progressive widening ordered by the heuristic
+    // prior, and --prune drops plays and discards known to be mistakes.
+    // --merge-clues searches one clue per set of clues that tell the target
+    // the same.
+    // --rollout-depth N cuts rollouts off after N moves and scores them with
+    // the static evaluator instead. --no-endgame keeps searching once the
+    // endgame is small enough to solve exactly.
+    let mut config = MctsConfig::default();
+    // --playouts N and --time SECONDS limit the search for each move, and
+    // --early-stop DELTA ends it once the best action is decided.
+    let mut budget = SearchBudget::default();
+    // --objective expected|perfect|risk-averse[:A]|strikeout[:PENALTY]
+    // picks what basic MCTS maximizes.
+    // --final-move max|robust|secure picks how the move is chosen after the
+    // search, and --report prints the statistics of every root action.
+    let mut report_root = false;
+    // --reuse-tree keeps the basic MCTS tree from one turn to the next.
+    let mut reuse_tree = false;
+    // --solve prints the best score possible with every card visible.
+    let mut solve = false;
+    // --seed N fixes the deck order, so runs can be compared on the same
+    // decks. It also seeds the agents' random choices, which makes the
+    // agent-based algorithms fully reproducible.
+    let mut seed: Option<usize> = None;
+    let mut flags: Vec<String> = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        flags.push(arg.clone());
+        match arg.as_str() {
+            "--particles" => {
+                num_particles = args.next()
+                    .and_then(|n| n.parse().ok())
+                    .expect("--particles requires a number");
+            },
+            "--infer" => {
+                infer = true;
+            },
+            "--algorithm" => {
+                algorithm = match args.next().as_deref() {
+                    Some("basic") => Algorithm::Basic,
+                    Some("ismcts") => Algorithm::Ismcts,
+                    Some("heuristic") => Algorithm::Heuristic,
+                    Some("sparta") => Algorithm::Sparta,
+                    Some("expectimax") => Algorithm::Expectimax,
+                    Some("conventions") => Algorithm::Conventions,
+                    Some("hat") => Algorithm::Hat,
+                    _ => panic!("--algorithm must be basic, ismcts, heuristic, sparta, expectimax, conventions or hat"),
+                };
+            },
+            "--samples" => {
+                samples = Some(args.next()
+                    .and_then(|n| n.parse().ok())
+                    .expect("--samples requires a number"));
+            },
+            "--depth" => {
+                depth = Some(args.next()
+                    .and_then(|n| n.parse().ok())
+                    .expect("--depth requires a number"));
+            },
+            "--mode" => {
+                config.search_mode = match args.next().as_deref() {
+                    Some("per-step") => SearchMode::PerStep,
+                    Some("per-playout") => SearchMode::PerPlayout,
+                    _ => panic!("--mode must be per-step or per-playout"),
+                };
+            },
+            "--rollout" => {
+                config.rollout = match args.next().as_deref() {
+                    Some("uniform") => Arc::new(UniformRollout),
+                    Some("heuristic") => Arc::new(HeuristicRollout::default()),
+                    _ => panic!("--rollout must be uniform or heuristic"),
+                };
+            },
+            "--selection" => {
+                config.selection_rule = match args.next().as_deref() {
+                    Some("ucb1") => SelectionRule::Ucb1,
+                    Some("ucb1-tuned") => SelectionRule::Ucb1Tuned,
+                    Some("puct") => SelectionRule::Puct,
+                    _ => panic!("--selection must be ucb1, ucb1-tuned or puct"),
+                };
+            },
+            "--exploration" => {
+                config.exploration = args.next()
+                    .and_then(|c| c.parse().ok())
+                    .expect("--exploration requires a number");
+            },
+            "--widening" => {
+                config.widening = Some(Widening::default());
+            },
+            "--prune" => {
+                config.prune_dominated = true;
+            },
+            "--no-endgame" => {
+                config.endgame = None;
+            },
+            "--solve" => {
+                solve = true;
+            },
+            "--canonical" => {
+                canonicalize = true;
+            },
+            "--merge-clues" => {
+                config.merge_clues = true;
+            },
+            "--rave" => {
+                config.rave_equivalence = Some(args.next()
+                    .and_then(|k| k.parse().ok())
+                    .expect("--rave requires an equivalence parameter"));
+            },
+            "--raw-rewards" => {
+                config.normalize_rewards = false;
+            },
+            "--objective" => {
+                let objective = args.next().expect("--objective requires an objective");
+                let mut parts = objective.splitn(2, ':');
+                let name = parts.next().unwrap();
+                let parameter = parts.next().map(|p| p.parse::<f64>().expect("Objective parameter must be a number"));
+                config.objective = match (name, parameter) {
+                    ("expected", None) => Objective::ExpectedScore,
+                    ("perfect", None) => Objective::PerfectGame,
+                    ("risk-averse", a) => {
+                        let a = a.unwrap_or(2.0);
+                        if a <= 0.0 {
+                            panic!("Risk aversion must be positive");
+                        }
+                        Objective::RiskAverse(a)
+                    },
+                    ("strikeout", penalty) => Objective::StrikeoutPenalty(penalty.unwrap_or(10.0)),
+                    _ => panic!("--objective must be expected, perfect, risk-averse[:A] or strikeout[:PENALTY]"),
+                };
+            },
+            "--final-move" => {
+                config.final_move = match args.next().as_deref() {
+                    Some("max") => FinalMovePolicy::MaxChild,
+                    Some("robust") => FinalMovePolicy::RobustChild,
+                    Some("secure") => FinalMovePolicy::SecureChild,
+                    _ => panic!("--final-move must be max, robust or secure"),
+                };
+            },
+            "--report" => {
+                report_root = true;
+            },
+            "--rollout-depth" => {
+                config.rollout_depth = Some(args.next()
+                    .and_then(|n| n.parse().ok())
+                    .expect("--rollout-depth requires a number"));
+            },
+            "--playouts" => {
+                budget.max_playouts = Some(args.next()
+                    .and_then(|n| n.parse().ok())
+                    .expect("--playouts requires a number"));
+            },
+            "--time" => {
+                let seconds: f64 = args.next()
+                    .and_then(|n| n.parse().ok())
+                    .expect("--time requires a number of seconds");
+                budget.time_limit = Some(Duration::from_millis((seconds * 1000.0) as u64));
+            },
+            "--early-stop" => {
+                budget.early_stop = Some(args.next()
+                    .and_then(|n| n.parse().ok())
+                    .expect("--early-stop requires a failure probability"));
+            },
+            "--reuse-tree" => {
+                reuse_tree = true;
+            },
+            "--seed" => {
+                seed = Some(args.next()
+                    .and_then(|n| n.parse().ok())
+                    .expect("--seed requires a number"));
+            },
+            _ => panic!("Unknown argument: {}", arg),
+        }
+    }
+
+    if let Some(flag) = flags.iter().find(|flag| !applies(flag, &algorithm)) {
+        panic!("{} does not apply to --algorithm {}", flag, algorithm.name());
+    }
+
+    let blueprint: Arc<dyn Agent> = Arc::new(HeuristicAgent);
+    let agent: Option<Arc<dyn Agent>> = match algorithm {
+        Algorithm::Basic | Algorithm::Ismcts => None,
+        Algorithm::Heuristic => Some(blueprint.clone()),
+        Algorithm::Sparta => {
+            let mut sparta = SpartaAgent::new(blueprint.clone());
+            if let Some(samples) = samples {
+                sparta.set_samples(samples);
+            }
+            Some(Arc::new(sparta))
+        },
+        Algorithm::Expectimax => {
+            let mut expectimax = ExpectimaxAgent::default();
+            if let Some(samples) = samples {
+                expectimax.set_samples(samples);
+            }
+            if let Some(depth) = depth {
+                expectimax.set_depth(depth);
+            }
+            Some(Arc::new(expectimax))
+        },
+        Algorithm::Conventions => Some(Arc::new(ConventionAgent)),
+        Algorithm::Hat => Some(Arc::new(HatAgent)),
+    };
+    let likelihood: Box<dyn ActionLikelihood> = if infer {
+        Box::new(BlueprintLikelihood::new(blueprint, 0.1))
+    } else {
+        Box::new(UniformLikelihood)
+    };
+
+    if budget.max_playouts.is_none() && budget.time_limit.is_none() {
+        budget.max_playouts = Some(500000);
+    }
 
     let mut deck: Vec<Card> = Vec::new();
     for (&c, &count) in deck_distribution().iter() {
@@ -21,44 +359,110 @@ fn main() {
         }
     }
 
-    // Shuffle the deck
+    // Shuffle the deck. deck_distribution is a HashMap, so sort first to make
+    // seeded shuffles reproducible.
+    deck.sort();
+    let mut rng: StdRng = match seed {
+        Some(seed) => SeedableRng::from_seed(&[seed, 1][..]),
+        None => StdRng::new().unwrap(),
+    };
+    let mut deck_rng: StdRng = match seed {
+        Some(seed) => SeedableRng::from_seed(&[seed][..]),
+        None => StdRng::new().unwrap(),
+    };
     for i in 0..deck.len() {
-        let j = Range::new(0, i+1).ind_sample(&mut rng);
+        let j = Range::new(0, i+1).ind_sample(&mut deck_rng);
         deck.swap(i, j);
     }
 
+    if algorithm == Algorithm::Basic {
+        println!("Objective: {:?}", config.objective);
+    }
     println!("Deck order:");
     for c in deck.iter() {
         println!("{:?}", c);
     }
 
-    println!("");
+    println!();
     let mut current_state = GameState::initial(&deck);
-    let mut batch_updates: Vec<(Vec<(u64, Action)>, f64)> = Vec::new();
+    let bound = score_bound(&current_state);
+    match bound.constraint {
+        Constraint::None => println!("Max possible score: {}", bound.score),
+        Constraint::BuriedCards { depth } => {
+            println!("Max possible score: {} (needed cards in the last {} of the deck)", bound.score, depth);
+        },
+        constraint => println!("Max possible score: {} ({:?})", bound.score, constraint),
+    }
+    if solve {
+        let mut solver = OmniscientSolver::new();
+        solver.set_max_nodes(1_000_000);
+        let solution = solver.solve(&current_state);
+        if solution.exact {
+            println!("Omniscient score: {}", solution.score);
+        } else {
+            println!("Omniscient score: at least {} (search limit reached)", solution.score);
+        }
+    }
+    let mut filters: HashMap<Player, ParticleFilter> = HashMap::new();
+    if num_particles > 0 {
+        let mut player = Player::Alice;
+        for _ in 0..4 {
+            let view = current_state.player_view(player);
+            filters.insert(player, ParticleFilter::new(view, player, num_particles, &mut rng));
+            player = player.next();
+        }
+    }
+    let mut tree: Option<MctsState> = None;
+    let start = Instant::now();
     let result = loop {
         let current_player = current_state.current_player();
         let current_view = current_state.current_view();
+        let filter = filters.get(&current_player);
 
-        let mut mcts = MctsState::new(current_view, 1.4);
-        for _ in 0..batches_per_move {
-            (0..batch_size).into_par_iter()
-                .map(|_| {
-                    let mut rng = rand::thread_rng();
-                    mcts.run_playout(&mut rng)
-                })
-                .collect_into_vec(&mut batch_updates);
-            for (updates, result) in batch_updates.drain(..) {
-                mcts.update(updates, result);
-            }
-        }
-
-        let action = mcts.choose_action(&mut rng);
+        let action = match algorithm {
+            Algorithm::Basic => {
+                let mut mcts = match tree.take() {
+                    Some(mcts) => mcts,
+                    None => MctsState::with_config(current_view, config.clone()),
+                };
+                let report = mcts.search(&budget, filter);
+                println!("Searched {} playouts in {:.2}s ({:?})",
+                    report.playouts, report.elapsed.as_secs_f64(), report.stop_reason);
+                if let Some(solution) = mcts.endgame_solution() {
+                    println!("Solved endgame over {} deals in {} nodes: expected reward {:.2}",
+                        solution.deals, solution.nodes, solution.expected_reward);
+                }
+                if report_root {
+                    for stats in mcts.root_report() {
+                        println!("  {:?}: {} visits, mean {:.2} [{:.2}, {:.2}]",
+                            stats.action, stats.visits, stats.mean, stats.lower, stats.upper);
+                    }
+                }
+                let action = mcts.choose_action(&mut rng);
+                if reuse_tree {
+                    tree = Some(mcts);
+                }
+                action
+            },
+            Algorithm::Ismcts => {
+                ismcts_action(current_view, filter, config.exploration, canonicalize, &budget, &mut rng)
+            },
+            Algorithm::Heuristic | Algorithm::Sparta | Algorithm::Expectimax | Algorithm::Conventions | Algorithm::Hat => {
+                agent.as_ref().unwrap().choose_action(&current_view, &mut rng)
+            },
+        };
 
         println!("{:?}: {:?}", current_player, action);
 
         match current_state.act(action) {
             ActionResult::Acted(completed_action) => {
                 println!("{:?}", completed_action);
+                for (&player, filter) in filters.iter_mut() {
+                    filter.observe_with(&completed_action, current_state.player_view(player), &*likelihood, &mut rng);
+                }
+                if let Some(ref mut mcts) = tree {
+                    mcts.advance(&completed_action, current_state.current_view());
+                }
             },
             ActionResult::Illegal(reason) => {
                 println!("{:?}", current_state);
@@ -73,4 +477,5 @@ fn main() {
         }
     };
     println!("Final result: {}", result);
+    println!("Time: {:.1}s", start.elapsed().as_secs_f64());
 }
@@ -0,0 +1,603 @@
+use std::collections::HashMap;
+
+use rand::Rng;
+use rand::distributions::{IndependentSample, Range};
+
+use hanabi::{card_key, Action, ActionError, Card, CardId, Clue, GameState, IllegalAction, Information, Player};
+use hanabi::{HAND_SIZE, PLAYERS, RANKS, SUITS};
+
+// A fixed-size representation of a game state for fast simulation.
+// Everything lives in small arrays, so the state is Copy and cloning it in
+// a playout costs no allocation. Cards are identified by their CardId as a
+// u8, card identities are encoded as suit * 5 + rank, and what each player
+// knows about a card from clues is a bitmask of the suits (bits 0-4) and
+// ranks (bits 5-9) it could still be.
+
+pub const NUM_CARDS: usize = 50;
+const NUM_PLAYERS: usize = 4;
+pub(crate) const NO_CARD: u8 = 0xff;
+pub(crate) const NO_PLAYER: u8 = 0xff;
+pub const ALL_KNOWLEDGE: u16 = 0x3ff;
+pub const SUIT_KNOWLEDGE: u16 = 0x1f;
+pub const RANK_KNOWLEDGE: u16 = 0x3e0;
+
+pub fn encode_card(card: Card) -> u8 {
+    card.suit as u8 * 5 + card.rank as u8
+}
+
+pub fn decode_card(code: u8) -> Card {
+    Card {
+        suit: SUITS[code as usize / 5],
+        rank: RANKS[code as usize % 5],
+    }
+}
+
+pub fn card_mask(card: Card) -> u16 {
+    (1 << card.suit as u16) | (1 << (5 + card.rank as u16))
+}
+
+pub fn clue_mask(clue: Clue) -> u16 {
+    match clue {
+        Clue::Suit(s) => 1 << s as u16,
+        Clue::Rank(r) => 1 << (5 + r as u16),
+    }
+}
+
+// The knowledge bits of the dimension a clue names.
+pub fn clue_dimension(clue: Clue) -> u16 {
+    match clue {
+        Clue::Suit(_) => SUIT_KNOWLEDGE,
+        Clue::Rank(_) => RANK_KNOWLEDGE,
+    }
+}
+
+// What is left of a knowledge bitmask once a card has been told whether it
+// matches a clue. A match pins down the clue's dimension.
+pub fn apply_clue(knowledge: u16, clue: Clue, matches: bool) -> u16 {
+    let mask = clue_mask(clue);
+    if matches {
+        knowledge & (!clue_dimension(clue) | mask)
+    } else {
+        knowledge & !mask
+    }
+}
+
+// Whether a card is allowed by a knowledge bitmask.
+pub fn knowledge_allows(knowledge: u16, card: Card) -> bool {
+    let mask = card_mask(card);
+    knowledge & mask == mask
+}
+
+// The number of copies of each card in the deck, indexed by encoded card.
+pub fn copies(code: u8) -> u8 {
+    match code % 5 {
+        0 => 3,
+        4 => 1,
+        _ => 2,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompactResult {
+    Acted,
+    Illegal(IllegalAction),
+    Error(ActionError),
+    Finished(i8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CompactState {
+    pub(crate) cards: [u8; NUM_CARDS],
+    pub(crate) knowledge: [u16; NUM_CARDS],
+    pub(crate) hands: [[u8; HAND_SIZE]; NUM_PLAYERS],
+    pub(crate) hand_sizes: [u8; NUM_PLAYERS],
+    // Bitsets over card ids.
+    pub(crate) played: u64,
+    pub(crate) discarded: u64,
+    // The number of cards played on each suit's pile.
+    pub(crate) piles: [u8; 5],
+    // The number of copies of each card discarded or misplayed.
+    pub(crate) discard_counts: [u8; 25],
+    pub(crate) deck_size: u8,
+    pub(crate) next_card: u8,
+    pub(crate) current: u8,
+    pub(crate) final_turn: u8,
+    pub(crate) clues: u8,
+    pub(crate) strikes: u8,
+}
+
+impl CompactState {
+    pub fn current_player(&self) -> Player {
+        Player::from_index(self.current as usize)
+    }
+
+    pub fn card(&self, c_id: u8) -> Option<Card> {
+        match self.cards[c_id as usize] {
+            NO_CARD => None,
+            code => Some(decode_card(code)),
+        }
+    }
+
+    pub fn hand(&self, player: Player) -> &[u8] {
+        let p = player.index();
+        &self.hands[p][..self.hand_sizes[p] as usize]
+    }
+
+    pub fn knowledge(&self, c_id: u8) -> u16 {
+        self.knowledge[c_id as usize]
+    }
+
+    pub fn clues(&self) -> u8 {
+        self.clues
+    }
+
+    pub fn strikes(&self) -> u8 {
+        self.strikes
+    }
+
+    pub fn deck_size(&self) -> u8 {
+        self.deck_size
+    }
+
+    // The number of cards played so far on the given suit's pile.
+    pub fn pile_height(&self, suit: usize) -> u8 {
+        self.piles[suit]
+    }
+
+    pub fn discard_count(&self, card: Card) -> u8 {
+        self.discard_counts[encode_card(card) as usize]
+    }
+
+    pub fn score(&self) -> i8 {
+        self.piles.iter().map(|&n| n as i8).sum()
+    }
+
+    // The highest score still reachable given the cards that have been lost:
+    // each pile can only grow up to the first rank with every copy gone.
+    pub fn max_score(&self) -> i8 {
+        (0..5u8).map(|suit| {
+            (0..5u8).find(|&r| {
+                let code = suit * 5 + r;
+                self.discard_counts[code as usize] == copies(code)
+            }).unwrap_or(5) as i8
+        }).sum()
+    }
+
+    // The most cards that can still be played, including on the current
+    // turn. Every play or discard draws while the deck lasts, and the last
+    // draw leaves one more turn for each player. Clues draw nothing, so this
+    // bounds plays rather than turns.
+    pub fn plays_left(&self) -> usize {
+        if self.final_turn == NO_PLAYER {
+            self.deck_size as usize + NUM_PLAYERS
+        } else {
+            (self.final_turn as usize + NUM_PLAYERS - self.current as usize) % NUM_PLAYERS + 1
+        }
+    }
+
+    pub fn is_playable(&self, card: Card) -> bool {
+        self.piles[card.suit as usize] == card.rank as u8
+    }
+
+    // Whether every card the clues on a card allow would be playable.
+    pub fn known_playable(&self, c_id: u8) -> bool {
+        let knowledge = self.knowledge[c_id as usize];
+        (0..25).map(decode_card)
+            .filter(|&c| knowledge_allows(knowledge, c))
+            .all(|c| self.is_playable(c))
+    }
+
+    // Whether the current player can tell from clues alone that an action is
+    // a mistake: playing a card that cannot be playable, or discarding one
+    // that must be critical.
+    pub fn is_dominated(&self, action: Action) -> bool {
+        let hand = self.hand(self.current_player());
+        let allowed = |i: usize| {
+            let knowledge = self.knowledge[hand[i] as usize];
+            (0..25).map(decode_card).filter(move |&c| knowledge_allows(knowledge, c))
+        };
+        match action {
+            Action::Play(i) => allowed(i).all(|c| !self.is_playable(c)),
+            Action::Discard(i) => allowed(i).all(|c| self.is_critical(c)),
+            Action::Clue(_, _) => false,
+        }
+    }
+
+    // Already played, or can never be played because a lower card is gone.
+    pub fn is_dead(&self, card: Card) -> bool {
+        let suit = card.suit as usize;
+        if self.piles[suit] > card.rank as u8 {
+            return true;
+        }
+        (self.piles[suit]..card.rank as u8).any(|r| {
+            let code = suit as u8 * 5 + r;
+            self.discard_counts[code as usize] == copies(code)
+        })
+    }
+
+    // Still needed, and the last remaining copy.
+    pub fn is_critical(&self, card: Card) -> bool {
+        let code = encode_card(card);
+        !self.is_dead(card) && self.discard_counts[code as usize] + 1 == copies(code)
+    }
+
+    pub fn legal_actions(&self) -> Vec<Action> {
+        let mut actions = Vec::new();
+        self.legal_actions_into(&mut actions);
+        actions
+    }
+
+    // Like legal_actions, but reuses the caller's buffer. Actions are in the
+    // same order as GameState::legal_actions produces them for each target.
+    pub fn legal_actions_into(&self, actions: &mut Vec<Action>) {
+        actions.clear();
+        let p = self.current as usize;
+        for i in 0..self.hand_sizes[p] as usize {
+            if self.clues < 8 {
+                actions.push(Action::Discard(i));
+            }
+            actions.push(Action::Play(i));
+        }
+
+        if self.clues > 0 {
+            for (t, &target) in PLAYERS.iter().enumerate() {
+                if t == p {
+                    continue;
+                }
+                let mut touched: u16 = 0;
+                for &c_id in self.hands[t][..self.hand_sizes[t] as usize].iter() {
+                    let code = self.cards[c_id as usize];
+                    if code != NO_CARD {
+                        touched |= card_mask(decode_card(code));
+                    }
+                }
+                for &suit in SUITS.iter() {
+                    let clue = Clue::Suit(suit);
+                    if touched & clue_mask(clue) != 0 {
+                        actions.push(Action::Clue(target, clue));
+                    }
+                }
+                for &rank in RANKS.iter() {
+                    let clue = Clue::Rank(rank);
+                    if touched & clue_mask(clue) != 0 {
+                        actions.push(Action::Clue(target, clue));
+                    }
+                }
+            }
+        }
+    }
+
+    // Follows exactly the same rules as GameState::act.
+    pub fn act(&mut self, action: Action) -> CompactResult {
+        let p = self.current as usize;
+        match action {
+            Action::Discard(i) => {
+                if i >= self.hand_sizes[p] as usize {
+                    return CompactResult::Illegal(IllegalAction::NoSuchCard);
+                }
+                if self.clues == 8 {
+                    return CompactResult::Illegal(IllegalAction::TooManyClues);
+                }
+                let c_id = self.hands[p][i];
+                let code = self.cards[c_id as usize];
+                if code == NO_CARD {
+                    return CompactResult::Error(ActionError::UnknownCard);
+                }
+
+                self.remove_from_hand(p, i);
+                self.discarded |= 1 << c_id;
+                self.discard_counts[code as usize] += 1;
+                self.clues += 1;
+                self.finish_turn(p)
+            },
+            Action::Play(i) => {
+                if i >= self.hand_sizes[p] as usize {
+                    return CompactResult::Illegal(IllegalAction::NoSuchCard);
+                }
+                let c_id = self.hands[p][i];
+                let code = self.cards[c_id as usize];
+                if code == NO_CARD {
+                    return CompactResult::Error(ActionError::UnknownCard);
+                }
+
+                self.remove_from_hand(p, i);
+                let card = decode_card(code);
+                if self.is_playable(card) {
+                    self.piles[card.suit as usize] += 1;
+                    self.played |= 1 << c_id;
+                    if card.rank as u8 == 4 && self.clues < 8 {
+                        self.clues += 1;
+                    }
+                } else {
+                    self.discarded |= 1 << c_id;
+                    self.discard_counts[code as usize] += 1;
+                    self.strikes += 1;
+                    if self.strikes == 3 {
+                        return CompactResult::Finished(0);
+                    }
+                }
+                self.finish_turn(p)
+            },
+            Action::Clue(target, clue) => {
+                let t = target.index();
+                if t == p {
+                    return CompactResult::Illegal(IllegalAction::CluedSelf);
+                }
+                if self.clues == 0 {
+                    return CompactResult::Illegal(IllegalAction::NoClues);
+                }
+
+                let mut any_match = false;
+                for &c_id in self.hands[t][..self.hand_sizes[t] as usize].iter() {
+                    let code = self.cards[c_id as usize];
+                    if code == NO_CARD {
+                        return CompactResult::Error(ActionError::UnknownCard);
+                    }
+                    any_match |= clue.matches(decode_card(code));
+                }
+                if !any_match {
+                    return CompactResult::Illegal(IllegalAction::NoMatchingCards);
+                }
+
+                for &c_id in self.hands[t][..self.hand_sizes[t] as usize].iter() {
+                    let matches = clue.matches(decode_card(self.cards[c_id as usize]));
+                    let k = &mut self.knowledge[c_id as usize];
+                    *k = apply_clue(*k, clue, matches);
+                }
+                self.clues -= 1;
+
+                if self.final_turn == p as u8 {
+                    return CompactResult::Finished(self.score());
+                }
+                self.current = ((p + 1) % NUM_PLAYERS) as u8;
+                CompactResult::Acted
+            },
+        }
+    }
+
+    fn remove_from_hand(&mut self, p: usize, i: usize) {
+        let size = self.hand_sizes[p] as usize;
+        // Like GameState, forget what was known about cards that leave a hand.
+        self.knowledge[self.hands[p][i] as usize] = ALL_KNOWLEDGE;
+        for j in i..size - 1 {
+            self.hands[p][j] = self.hands[p][j + 1];
+        }
+        self.hands[p][size - 1] = NO_CARD;
+        self.hand_sizes[p] -= 1;
+    }
+
+    fn finish_turn(&mut self, p: usize) -> CompactResult {
+        if self.final_turn == p as u8 {
+            return CompactResult::Finished(self.score());
+        }
+
+        if self.deck_size > 0 {
+            let size = self.hand_sizes[p] as usize;
+            self.hands[p][size] = self.next_card;
+            self.hand_sizes[p] += 1;
+            self.next_card += 1;
+            self.deck_size -= 1;
+            if self.deck_size == 0 {
+                self.final_turn = p as u8;
+            }
+        }
+
+        self.current = ((p + 1) % NUM_PLAYERS) as u8;
+        CompactResult::Acted
+    }
+
+    // Fills in every card that is not known, in hands and in the deck, with
+    // a random deal consistent with the clues and the cards seen. Hidden
+    // cards in hand are dealt first, the most constrained first, each among
+    // the remaining copies its clues allow; the rest are shuffled into the
+    // deck. When clues constrain several cards the deal is not exactly
+    // uniform. Unlike GameState::determinize, the result depends only on the
+    // rng.
+    pub fn determinize<R: Rng>(&mut self, rng: &mut R) {
+        let total_cards = self.next_card as usize + self.deck_size as usize;
+        let mut remaining = [0u8; 25];
+        for code in 0..25u8 {
+            remaining[code as usize] = copies(code);
+        }
+        for &code in self.cards[..total_cards].iter() {
+            if code != NO_CARD {
+                remaining[code as usize] -= 1;
+            }
+        }
+        let allowed = |state: &CompactState, c_id: usize, counts: &[u8; 25]| -> Vec<u8> {
+            (0..25u8).filter(|&code| {
+                counts[code as usize] > 0 && knowledge_allows(state.knowledge[c_id], decode_card(code))
+            }).collect()
+        };
+
+        let mut hidden_hand: Vec<usize> = (0..self.next_card as usize)
+            .filter(|&c_id| self.cards[c_id] == NO_CARD && (self.played | self.discarded) & (1 << c_id) == 0)
+            .collect();
+        hidden_hand.sort_by_key(|&c_id| allowed(self, c_id, &remaining).len());
+
+        // Start over if the cards dealt so far leave a later card with no
+        // possibilities.
+        let mut counts;
+        'deal: loop {
+            counts = remaining;
+            for &c_id in hidden_hand.iter() {
+                let options = allowed(self, c_id, &counts);
+                let total: usize = options.iter().map(|&code| counts[code as usize] as usize).sum();
+                if total == 0 {
+                    continue 'deal;
+                }
+                let mut pick = Range::new(0, total).ind_sample(rng);
+                for &code in options.iter() {
+                    if pick < counts[code as usize] as usize {
+                        self.cards[c_id] = code;
+                        counts[code as usize] -= 1;
+                        break;
+                    }
+                    pick -= counts[code as usize] as usize;
+                }
+            }
+            break;
+        }
+
+        let mut pool: Vec<u8> = Vec::new();
+        for code in 0..25u8 {
+            for _ in 0..counts[code as usize] {
+                pool.push(code);
+            }
+        }
+        rng.shuffle(&mut pool);
+        for (c_id, code) in (self.next_card as usize..total_cards).zip(pool) {
+            self.cards[c_id] = code;
+        }
+    }
+
+    // Plays uniformly random legal actions until the game ends. All cards
+    // must be known.
+    pub fn random_playout<R: Rng>(&mut self, rng: &mut R) -> i8 {
+        let mut actions = Vec::with_capacity(40);
+        loop {
+            self.legal_actions_into(&mut actions);
+            let action = actions[Range::new(0, actions.len()).ind_sample(rng)];
+            match self.act(action) {
+                CompactResult::Acted => {},
+                CompactResult::Finished(score) => return score,
+                CompactResult::Illegal(_) => panic!("Random playout chose an illegal action!"),
+                CompactResult::Error(_) => panic!("Random playout needs every card to be known"),
+            }
+        }
+    }
+}
+
+impl<'a> From<&'a GameState> for CompactState {
+    fn from(state: &'a GameState) -> CompactState {
+        let mut cards = [NO_CARD; NUM_CARDS];
+        for (&CardId(id), &c) in state.card_map.iter() {
+            cards[id] = encode_card(c);
+        }
+
+        let mut knowledge = [ALL_KNOWLEDGE; NUM_CARDS];
+        for (&CardId(id), infos) in state.information.iter() {
+            for &Information(clue, matches) in infos.iter() {
+                knowledge[id] = apply_clue(knowledge[id], clue, matches);
+            }
+        }
+
+        let mut hands = [[NO_CARD; HAND_SIZE]; NUM_PLAYERS];
+        let mut hand_sizes = [0; NUM_PLAYERS];
+        for (&player, hand) in state.hands.iter() {
+            let p = player.index();
+            for (i, &CardId(id)) in hand.iter().enumerate() {
+                hands[p][i] = id as u8;
+            }
+            hand_sizes[p] = hand.len() as u8;
+        }
+
+        let mut played = 0;
+        for &CardId(id) in state.played_cards.iter() {
+            played |= 1 << id;
+        }
+        let mut discarded = 0;
+        let mut discard_counts = [0; 25];
+        for &CardId(id) in state.discarded_cards.iter() {
+            discarded |= 1 << id;
+            if cards[id] != NO_CARD {
+                discard_counts[cards[id] as usize] += 1;
+            }
+        }
+
+        let mut piles = [0; 5];
+        for (&suit, &rank) in state.piles.iter() {
+            piles[suit as usize] = rank as u8 + 1;
+        }
+
+        let CardId(next_card) = state.next_card_id;
+        CompactState {
+            cards,
+            knowledge,
+            hands,
+            hand_sizes,
+            played,
+            discarded,
+            piles,
+            discard_counts,
+            deck_size: state.deck_size as u8,
+            next_card: next_card as u8,
+            current: state.current_turn.index() as u8,
+            final_turn: state.final_turn.map_or(NO_PLAYER, |p| p.index() as u8),
+            clues: state.clues,
+            strikes: state.strikes,
+        }
+    }
+}
+
+// The compact state does not keep the action log, so the resulting state
+// has an empty log, and clue information is recorded as the equivalent set
+// of negative clues.
+impl From<CompactState> for GameState {
+    fn from(state: CompactState) -> GameState {
+        let mut card_map = HashMap::new();
+        let mut card_hash = 0;
+        for (id, &code) in state.cards.iter().enumerate() {
+            if code != NO_CARD {
+                card_map.insert(CardId(id), decode_card(code));
+                card_hash ^= card_key(CardId(id), decode_card(code));
+            }
+        }
+
+        let mut hands = HashMap::new();
+        let mut information = HashMap::new();
+        for &player in PLAYERS.iter() {
+            let hand: Vec<CardId> = state.hand(player).iter().map(|&id| CardId(id as usize)).collect();
+            for &c_id in hand.iter() {
+                let CardId(id) = c_id;
+                let k = state.knowledge[id];
+                if k == ALL_KNOWLEDGE {
+                    continue;
+                }
+                let mut infos = Vec::new();
+                for &suit in SUITS.iter() {
+                    if k & clue_mask(Clue::Suit(suit)) == 0 {
+                        infos.push(Information(Clue::Suit(suit), false));
+                    }
+                }
+                for &rank in RANKS.iter() {
+                    if k & clue_mask(Clue::Rank(rank)) == 0 {
+                        infos.push(Information(Clue::Rank(rank), false));
+                    }
+                }
+                information.insert(c_id, infos);
+            }
+            hands.insert(player, hand);
+        }
+
+        let ids = |set: u64| (0..NUM_CARDS).filter(|&id| set & (1 << id) != 0).map(CardId).collect::<Vec<CardId>>();
+
+        let mut piles = HashMap::new();
+        for (suit, &height) in state.piles.iter().enumerate() {
+            if height > 0 {
+                piles.insert(SUITS[suit], RANKS[height as usize - 1]);
+            }
+        }
+
+        GameState {
+            card_map,
+            deck_size: state.deck_size as usize,
+            next_card_id: CardId(state.next_card as usize),
+            current_turn: state.current_player(),
+            final_turn: match state.final_turn {
+                NO_PLAYER => None,
+                p => Some(Player::from_index(p as usize)),
+            },
+            hands,
+            played_cards: ids(state.played),
+            discarded_cards: ids(state.discarded),
+            piles,
+            clues: state.clues,
+            strikes: state.strikes,
+            information,
+            action_log: Vec::new(),
+            card_hash,
+            history_hash: 0,
+        }
+    }
+}
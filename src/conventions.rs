@@ -0,0 +1,606 @@
+use rand::Rng;
+
+use agent::Agent;
+use compact::{copies, decode_card, encode_card, CompactState, NUM_CARDS};
+use hanabi::{Action, CardId, Clue, CompletedAction, GameState, Player, HAND_SIZE, PLAYERS};
+
+// A hand-written bot following a small set of standard conventions:
+//
+//  - A player's chop is their oldest untouched card, the one they discard.
+//  - A clue's focus is the chop if the clue newly touches it, else the
+//    newest newly touched card, else the newest touched card.
+//  - A clue focused elsewhere asks for the focus to be played, possibly
+//    once touched cards in other hands are played first. A clue focused on
+//    the chop may instead save it, if it could be a critical card, a 5 or
+//    a 2 with no other copy in sight.
+//  - Good touch: clues only touch cards that will be needed, so touched
+//    cards are never trash or copies of other touched cards.
+//  - Finesse: if the clue's focus is one card away from playable and the
+//    missing card is nowhere to be seen touched, the player right after
+//    the giver blind-plays their newest untouched card, which must be it.
+//    If one of their touched cards could be the missing card, they play
+//    that instead (a prompt).
+//
+// What a clue meant is worked out from common knowledge at the time it was
+// given: the piles, the discards, the clues and what earlier clues meant.
+// A touched card counts as known to everyone only once those pin it down to
+// a single identity, so all players, the target included, agree on what
+// each clue meant. The actor's own plays and discards also use what they
+// can see. Cards are sets of possible identities, as bit masks over the 25
+// cards.
+
+type Mask = u32;
+
+const ALL_CARDS: Mask = (1 << 25) - 1;
+
+fn bit(code: u8) -> Mask {
+    1 << code
+}
+
+fn clue_mask(clue: Clue) -> Mask {
+    (0..25u8).filter(|&code| clue.matches(decode_card(code))).fold(0, |mask, code| mask | bit(code))
+}
+
+// Cards that are playable once the touched cards that continue each pile
+// are played. With finesse set, also the card after those, which a finesse
+// can make playable.
+fn chain_mask(piles: &[u8; 5], touched: Mask, finesse: bool) -> Mask {
+    let mut mask = 0;
+    for suit in 0..5u8 {
+        let mut height = piles[suit as usize];
+        while height < 5 {
+            let code = suit * 5 + height;
+            mask |= bit(code);
+            if touched & bit(code) == 0 {
+                break;
+            }
+            height += 1;
+        }
+        if finesse && height + 1 < 5 {
+            mask |= bit(suit * 5 + height + 1);
+        }
+    }
+    mask
+}
+
+fn dead_mask(piles: &[u8; 5], discards: &[u8; 25]) -> Mask {
+    let mut mask = 0;
+    for suit in 0..5u8 {
+        let lost = (piles[suit as usize]..5).find(|&r| discards[(suit * 5 + r) as usize] == copies(suit * 5 + r));
+        for rank in 0..5u8 {
+            if rank < piles[suit as usize] || lost.is_some_and(|lost| rank > lost) {
+                mask |= bit(suit * 5 + rank);
+            }
+        }
+    }
+    mask
+}
+
+// Cards worth saving from the chop: the last copy of a needed card, any 5,
+// and any 2 with no other copy in the hands counted.
+fn save_mask(piles: &[u8; 5], discards: &[u8; 25], hand_counts: &[u8; 25]) -> Mask {
+    let dead = dead_mask(piles, discards);
+    (0..25u8).filter(|&code| {
+        let rank = code % 5;
+        dead & bit(code) == 0
+            && (discards[code as usize] + 1 == copies(code) || rank == 4 || (rank == 1 && hand_counts[code as usize] == 0))
+    }).fold(0, |mask, code| mask | bit(code))
+}
+
+// What a clue said about its focus.
+#[derive(Debug, Clone, Copy)]
+struct Focus {
+    // The cards it may be if the clue asked for a play.
+    play: Mask,
+    // The cards in play that need a finesse. The next action either plays
+    // the missing card or rules them out.
+    finesse: Mask,
+    // If the clue newly touched the chop, the cards worth saving it may be.
+    save: Mask,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct LastClue {
+    target: Player,
+    focus: usize,
+}
+
+// The public record of the clues: which cards have been touched, what the
+// clues say about every card, and what each focus meant at the time.
+struct Notes {
+    touched: [bool; NUM_CARDS],
+    clued: [Mask; NUM_CARDS],
+    focus: [Option<Focus>; NUM_CARDS],
+    // The previous action, if it was a clue.
+    last_clue: Option<LastClue>,
+}
+
+impl Notes {
+    fn new(view: &GameState) -> Notes {
+        let mut notes = Notes {
+            touched: [false; NUM_CARDS],
+            clued: [ALL_CARDS; NUM_CARDS],
+            focus: [None; NUM_CARDS],
+            last_clue: None,
+        };
+
+        // Replay the draws to know every hand at every turn.
+        let CardId(next_card) = view.next_card_id;
+        let total_cards = next_card + view.deck_size;
+        let mut hands: Vec<Vec<usize>> = (0..PLAYERS.len())
+            .map(|p| (0..HAND_SIZE).map(|slot| slot * PLAYERS.len() + p).collect())
+            .collect();
+        let mut drawn = PLAYERS.len() * HAND_SIZE;
+        let mut piles = [0u8; 5];
+        let mut discards = [0u8; 25];
+
+        for (turn, action) in view.action_log().iter().enumerate() {
+            let actor = turn % PLAYERS.len();
+            if let Some(last) = notes.last_clue.take() {
+                let focus = notes.focus[last.focus].as_mut().unwrap();
+                let finessed = match *action {
+                    CompletedAction::Played(_, c) if piles[c.suit as usize] == c.rank as u8 && c.rank as u8 + 1 < 5 => {
+                        focus.finesse & bit(encode_card(c) + 1)
+                    },
+                    _ => 0,
+                };
+                focus.play &= !(focus.finesse & !finessed);
+                focus.finesse = finessed;
+            }
+            match *action {
+                CompletedAction::Discarded(i, c) | CompletedAction::Played(i, c) => {
+                    hands[actor].remove(i);
+                    let code = encode_card(c);
+                    let suit = c.suit as usize;
+                    let played = matches!(*action, CompletedAction::Played(..)) && piles[suit] == c.rank as u8;
+                    if played {
+                        piles[suit] += 1;
+                    } else {
+                        discards[code as usize] += 1;
+                    }
+                    if drawn < total_cards {
+                        hands[actor].push(drawn);
+                        drawn += 1;
+                    }
+                },
+                CompletedAction::Clued(target, clue, ref matching) => {
+                    let hand = &hands[target.index()];
+                    let matching: Vec<usize> = matching.iter().map(|&CardId(id)| id).collect();
+                    let chop = hand.iter().cloned().find(|&id| !notes.touched[id]);
+                    let new: Vec<usize> = hand.iter().cloned()
+                        .filter(|id| matching.contains(id) && !notes.touched[*id])
+                        .collect();
+                    let chop_focus = chop.is_some_and(|chop| new.contains(&chop));
+                    let focus = if chop_focus {
+                        chop.unwrap()
+                    } else if let Some(&newest) = new.last() {
+                        newest
+                    } else {
+                        *hand.iter().rev().find(|id| matching.contains(id)).unwrap()
+                    };
+
+                    let in_hands: Vec<usize> = hands.iter().flatten().cloned().collect();
+                    notes.focus[focus] = Some(notes.meaning(&in_hands, &piles, &discards, chop_focus));
+                    for &id in hand.iter() {
+                        if matching.contains(&id) {
+                            notes.touched[id] = true;
+                            notes.clued[id] &= clue_mask(clue);
+                        } else {
+                            notes.clued[id] &= !clue_mask(clue);
+                        }
+                    }
+                    notes.last_clue = Some(LastClue {
+                        target,
+                        focus,
+                    });
+                },
+            }
+        }
+        notes
+    }
+
+    // What a card is known to be by everyone: a touched card whose clues,
+    // once narrowed to what its focus meant, leave a single live identity.
+    fn common_identity(&self, id: usize, dead: Mask) -> Option<u8> {
+        if !self.touched[id] {
+            return None;
+        }
+        let mut mask = self.clued[id] & !dead;
+        if let Some(focus) = self.focus[id] {
+            let meant = mask & (focus.play | focus.save);
+            if meant != 0 {
+                mask = meant;
+            }
+        }
+        if mask.count_ones() == 1 {
+            Some(mask.trailing_zeros() as u8)
+        } else {
+            None
+        }
+    }
+
+    // What a clue focused on a card means, from common knowledge about the
+    // cards in hand and the board.
+    fn meaning(&self, in_hands: &[usize], piles: &[u8; 5], discards: &[u8; 25], chop_focus: bool) -> Focus {
+        let dead = dead_mask(piles, discards);
+        let mut touched = 0;
+        let mut hand_counts = [0u8; 25];
+        for &id in in_hands {
+            if let Some(code) = self.common_identity(id, dead) {
+                hand_counts[code as usize] += 1;
+                touched |= bit(code);
+            }
+        }
+        let direct = chain_mask(piles, touched, false);
+        Focus {
+            play: chain_mask(piles, touched, true) & !dead,
+            finesse: chain_mask(piles, touched, true) & !direct & !dead,
+            save: if chop_focus { save_mask(piles, discards, &hand_counts) } else { 0 },
+        }
+    }
+}
+
+// How a clue would land: the cards it touches, the newly touched ones, and
+// its focus.
+struct ClueOutcome {
+    touched: Vec<usize>,
+    new: Vec<usize>,
+    focus: usize,
+    chop_focus: bool,
+}
+
+// The actor's reasoning about the current position.
+struct Position<'a> {
+    view: &'a GameState,
+    state: CompactState,
+    me: Player,
+    notes: Notes,
+    piles: [u8; 5],
+    discards: [u8; 25],
+    // Codes of the touched cards in other players' hands.
+    visible_touched: Mask,
+    // Codes with every copy played, discarded or in another player's hand.
+    exhausted: Mask,
+    dead: Mask,
+    playable: Mask,
+    // Cards that are playable now or once visible touched cards are played.
+    expected: Mask,
+}
+
+impl<'a> Position<'a> {
+    fn new(view: &'a GameState) -> Position<'a> {
+        let state = CompactState::from(view);
+        let me = view.current_player();
+        let notes = Notes::new(view);
+        let mut piles = [0u8; 5];
+        let mut discards = [0u8; 25];
+        for (suit, pile) in piles.iter_mut().enumerate() {
+            *pile = state.pile_height(suit);
+        }
+        for code in 0..25u8 {
+            discards[code as usize] = state.discard_count(decode_card(code));
+        }
+
+        let mut visible_touched = 0;
+        let mut seen = [0u8; 25];
+        for code in 0..25u8 {
+            let (suit, rank) = (code / 5, code % 5);
+            seen[code as usize] = discards[code as usize] + if rank < piles[suit as usize] { 1 } else { 0 };
+        }
+        for &player in PLAYERS.iter().filter(|&&p| p != me) {
+            for &CardId(id) in view.hand(player) {
+                let code = encode_card(view.known_card(CardId(id)).unwrap());
+                seen[code as usize] += 1;
+                if notes.touched[id] {
+                    visible_touched |= bit(code);
+                }
+            }
+        }
+        let exhausted = (0..25u8).filter(|&code| seen[code as usize] >= copies(code)).fold(0, |mask, code| mask | bit(code));
+        let playable = (0..5u8).filter(|&suit| piles[suit as usize] < 5).fold(0, |mask, suit| mask | bit(suit * 5 + piles[suit as usize]));
+
+        Position {
+            view,
+            state,
+            me,
+            notes,
+            piles,
+            discards,
+            visible_touched,
+            exhausted,
+            dead: dead_mask(&piles, &discards),
+            playable,
+            expected: chain_mask(&piles, visible_touched, false),
+        }
+    }
+
+    fn identity(&self, id: usize) -> Option<u8> {
+        self.view.known_card(CardId(id)).map(encode_card)
+    }
+
+    fn hand(&self, player: Player) -> Vec<usize> {
+        self.view.hand(player).iter().map(|&CardId(id)| id).collect()
+    }
+
+    fn chop(&self, player: Player) -> Option<usize> {
+        self.hand(player).into_iter().find(|&id| !self.notes.touched[id])
+    }
+
+    // What the clues on a card allow it to be.
+    fn knowledge(&self, id: usize) -> Mask {
+        (0..25u8).filter(|&code| self.view.card_consistent(CardId(id), decode_card(code)))
+            .fold(0, |mask, code| mask | bit(code))
+    }
+
+    // What one of the actor's own cards may be, given the clues, the cards
+    // in sight, good touch and the meaning of the clue that focused it.
+    // Each convention only narrows the set if it leaves something.
+    fn candidates(&self, id: usize) -> Mask {
+        let mut candidates = self.knowledge(id) & !self.exhausted;
+        if let Some(focus) = self.notes.focus[id] {
+            // Once all the clue could have meant is trash, another copy was
+            // played first and this one is trash too.
+            let meant = candidates & (focus.save | focus.play);
+            if meant != 0 && meant & !self.dead == 0 {
+                return meant;
+            }
+        }
+        if self.notes.touched[id] {
+            let good_touch = candidates & !self.dead & !self.visible_touched;
+            if good_touch != 0 {
+                candidates = good_touch;
+            }
+            if let Some(focus) = self.notes.focus[id] {
+                // Any finesse or prompt has been resolved by the target's
+                // turn, so what is not yet expected was never meant.
+                let meant = candidates & (focus.save | (focus.play & self.expected));
+                if meant != 0 {
+                    candidates = meant;
+                }
+            }
+        }
+        candidates
+    }
+
+    fn outcome(&self, target: Player, clue: Clue) -> ClueOutcome {
+        let hand = self.hand(target);
+        let touched: Vec<usize> = hand.iter().cloned()
+            .filter(|&id| self.identity(id).is_some_and(|code| clue.matches(decode_card(code))))
+            .collect();
+        let new: Vec<usize> = touched.iter().cloned().filter(|&id| !self.notes.touched[id]).collect();
+        let chop = self.chop(target);
+        let chop_focus = chop.is_some_and(|chop| new.contains(&chop));
+        let focus = if chop_focus {
+            chop.unwrap()
+        } else {
+            *new.last().or(touched.last()).unwrap()
+        };
+        ClueOutcome {
+            touched,
+            new,
+            focus,
+            chop_focus,
+        }
+    }
+
+    // Whether a clue only newly touches cards that are still needed and not
+    // already touched elsewhere.
+    fn good_touch(&self, outcome: &ClueOutcome) -> bool {
+        let mut seen = self.visible_touched;
+        for &id in outcome.new.iter() {
+            let code = self.identity(id).unwrap();
+            if (self.dead | seen) & bit(code) != 0 {
+                return false;
+            }
+            seen |= bit(code);
+        }
+        true
+    }
+
+    // What the target of a clue will take its focus to be on their turn,
+    // once any finesse has been played. Like everyone else, they read it
+    // from common knowledge.
+    fn target_reading(&self, clue: Clue, outcome: &ClueOutcome) -> Mask {
+        let in_hands: Vec<usize> = PLAYERS.iter().flat_map(|&p| self.hand(p)).collect();
+        let meaning = self.notes.meaning(&in_hands, &self.piles, &self.discards, outcome.chop_focus);
+        let play = meaning.play & !meaning.finesse;
+        self.knowledge(outcome.focus) & clue_mask(clue) & !self.dead & (meaning.save | play)
+    }
+
+    fn choose_action(&self) -> Action {
+        let hand = self.hand(self.me);
+        let candidates: Vec<Mask> = hand.iter().map(|&id| self.candidates(id)).collect();
+        let clues = self.state.clues();
+
+        if self.state.strikes() < 2 {
+            if let Some(action) = self.finesse_response(&hand, &candidates) {
+                return action;
+            }
+        }
+        if clues > 0 {
+            if let Some(action) = self.save_clue() {
+                return action;
+            }
+        }
+        // On the last life, only play what the clues themselves prove.
+        let proven = |i: usize| self.state.strikes() < 2 || self.knowledge(hand[i]) & !self.exhausted & !self.playable == 0;
+        if let Some(i) = (0..hand.len()).find(|&i| candidates[i] != 0 && candidates[i] & !self.playable == 0 && proven(i)) {
+            return Action::Play(i);
+        }
+        if clues > 0 {
+            if let Some(action) = self.play_clue() {
+                return action;
+            }
+        }
+        if clues < 8 {
+            let trash = candidates.iter().position(|&c| c & !self.dead == 0);
+            let chop = hand.iter().position(|&id| !self.notes.touched[id]);
+            if let Some(i) = trash.or(chop) {
+                return Action::Discard(i);
+            }
+        }
+        if clues > 0 {
+            return self.stall_clue();
+        }
+        Action::Discard(0)
+    }
+
+    // Right after a clue that can only be a finesse or a prompt, the player
+    // after the giver plays the missing card. Nobody finesses on the last
+    // life.
+    fn finesse_response(&self, hand: &[usize], candidates: &[Mask]) -> Option<Action> {
+        let last = self.notes.last_clue?;
+        if last.target == self.me {
+            return None;
+        }
+        let code = self.identity(last.focus)?;
+        let focus = self.notes.focus[last.focus]?;
+        let (suit, rank) = (code / 5, code % 5);
+        if (self.expected | focus.save) & bit(code) != 0 || rank != self.piles[suit as usize] + 1 {
+            return None;
+        }
+        let missing = code - 1;
+        if let Some(i) = (0..hand.len()).find(|&i| self.notes.touched[hand[i]] && candidates[i] & bit(missing) != 0) {
+            return Some(Action::Play(i));
+        }
+        hand.iter().rposition(|&id| !self.notes.touched[id]).map(Action::Play)
+    }
+
+    // Saves the next player's chop if it is worth saving and they have
+    // nothing better to do than discard it.
+    fn save_clue(&self) -> Option<Action> {
+        let next = self.me.next();
+        let hand = self.hand(next);
+        let has_play = hand.iter().any(|&id| {
+            self.notes.touched[id] && self.identity(id).is_some_and(|code| self.playable & bit(code) != 0)
+        });
+        if has_play {
+            return None;
+        }
+        let chop = self.chop(next)?;
+        let code = self.identity(chop)?;
+        let others = PLAYERS.iter()
+            .filter(|&&p| p != self.me)
+            .flat_map(|&p| self.hand(p))
+            .filter(|&id| id != chop && self.identity(id) == Some(code))
+            .count();
+        let mut hand_counts = [0u8; 25];
+        hand_counts[code as usize] = others as u8;
+        let worth_saving = save_mask(&self.piles, &self.discards, &hand_counts) & bit(code) != 0
+            && self.visible_touched & bit(code) == 0;
+        if !worth_saving {
+            return None;
+        }
+
+        let card = decode_card(code);
+        let clues = [Clue::Rank(card.rank), Clue::Suit(card.suit)];
+        let clue = clues.iter().cloned()
+            .find(|&clue| self.good_touch(&self.outcome(next, clue)))
+            .unwrap_or(clues[0]);
+        Some(Action::Clue(next, clue))
+    }
+
+    // The most useful clue that gets a card played, directly, after touched
+    // cards, or through a finesse on the next player.
+    fn play_clue(&self) -> Option<Action> {
+        let mut best: Option<(f64, Action)> = None;
+        for action in self.state.legal_actions() {
+            let (target, clue) = match action {
+                Action::Clue(target, clue) => (target, clue),
+                _ => continue,
+            };
+            let outcome = self.outcome(target, clue);
+            if outcome.new.is_empty() || !self.good_touch(&outcome) {
+                continue;
+            }
+            let code = self.identity(outcome.focus).unwrap();
+            let mut value = if self.expected & bit(code) != 0 {
+                let reading = self.target_reading(clue, &outcome);
+                if reading & bit(code) == 0 {
+                    continue;
+                } else if reading & !self.expected == 0 {
+                    2.0
+                } else {
+                    // The target will hold it as a save.
+                    0.5
+                }
+            } else if self.finesse_possible(target, clue, &outcome, code) {
+                3.0
+            } else {
+                continue;
+            };
+            let useful = outcome.new.iter()
+                .filter(|&&id| id != outcome.focus && self.expected & bit(self.identity(id).unwrap()) != 0)
+                .count();
+            value += 0.5 * useful as f64 + 0.1 * outcome.touched.len() as f64;
+            if best.is_none_or(|(v, _)| value > v) {
+                best = Some((value, action));
+            }
+        }
+        best.map(|(_, action)| action)
+    }
+
+    // Whether a clue on a card one away from playable sets up a finesse that
+    // the next player will read correctly.
+    fn finesse_possible(&self, target: Player, clue: Clue, outcome: &ClueOutcome, code: u8) -> bool {
+        let next = self.me.next();
+        let (suit, rank) = (code / 5, code % 5);
+        if target == next || rank != self.piles[suit as usize] + 1 || self.state.strikes() >= 2 {
+            return false;
+        }
+        let missing = code - 1;
+        if self.visible_touched & bit(missing) != 0 {
+            return false;
+        }
+        // A chop focus that reads as a save is not a finesse.
+        if outcome.chop_focus && self.target_reading(clue, outcome) & !self.expected & !bit(code) != 0 {
+            return false;
+        }
+        let hand = self.hand(next);
+        let prompt = hand.iter().any(|&id| self.notes.touched[id] && self.knowledge(id) & bit(missing) != 0);
+        let finesse_card = hand.iter().rev().cloned().find(|&id| !self.notes.touched[id]);
+        !prompt && finesse_card.is_some_and(|id| self.identity(id) == Some(missing))
+    }
+
+    // Whether a clue that is not meant as a play could still be taken as
+    // one: by the target, if all they can read into the focus is playable
+    // soon, or by the next player as a finesse.
+    fn misread(&self, target: Player, clue: Clue, outcome: &ClueOutcome) -> bool {
+        let code = self.identity(outcome.focus).unwrap();
+        let (suit, rank) = (code / 5, code % 5);
+        let reading = self.target_reading(clue, outcome);
+        if self.expected & bit(code) != 0 || (reading & bit(code) != 0 && reading & !self.expected != 0) {
+            return false;
+        }
+        let finesse = target != self.me.next() && rank == self.piles[suit as usize] + 1;
+        finesse || (reading != 0 && reading & !self.expected == 0)
+    }
+
+    // With nothing better to do and clues to spare, a clue that cannot be
+    // misread, preferring good touch and then more newly touched cards.
+    fn stall_clue(&self) -> Action {
+        let legal_actions = self.state.legal_actions();
+        let mut best: Option<((bool, bool, usize), Action)> = None;
+        for &action in legal_actions.iter() {
+            if let Action::Clue(target, clue) = action {
+                let outcome = self.outcome(target, clue);
+                let key = (!self.misread(target, clue, &outcome), self.good_touch(&outcome), outcome.new.len());
+                if best.is_none_or(|(k, _)| key > k) {
+                    best = Some((key, action));
+                }
+            }
+        }
+        best.map_or(legal_actions[0], |(_, action)| action)
+    }
+}
+
+// Plays by the conventions above. It is deterministic and needs no search,
+// which makes it a fast baseline, and a blueprint for agents that search.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConventionAgent;
+
+impl Agent for ConventionAgent {
+    fn choose_action(&self, view: &GameState, _rng: &mut dyn Rng) -> Action {
+        Position::new(view).choose_action()
+    }
+}
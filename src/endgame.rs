@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use basic_mcts::Objective;
+use compact::{copies, decode_card, knowledge_allows, CompactResult, CompactState, NO_CARD};
+use hanabi::{Action, GameState, Player};
+use prior::HeuristicPrior;
+use solver::compact_score_bound;
+
+// An exact expectimax solver for endgames. When the deck is nearly gone and
+// few cards are hidden, every deal of the hidden cards consistent with a
+// view can be listed, and each is equally likely.
+//
+// Every player, not just the one solving, decides from their own view: the
+// action taken in a deal is the one that maximizes the total reward over all
+// deals consistent with what the player to act sees there, which include
+// every way their own hand and the deck could be filled in. Beliefs only
+// use what players have seen, not what partners' choices imply about their
+// cards, so the value is that of a team that plays this way, and never
+// credits a player with knowledge of cards they cannot see. The number of
+// positions grows quickly with the hidden cards, so the solver gives up
+// past its limits and leaves the decision to the search.
+
+#[derive(Debug, Clone)]
+pub struct EndgameConfig {
+    // Only solve once the deck has at most this many cards.
+    pub max_deck_size: usize,
+    // Give up if more deals than this are consistent with any player's view.
+    pub max_deals: usize,
+    // Give up after evaluating this many views and deals.
+    pub max_nodes: usize,
+}
+
+impl Default for EndgameConfig {
+    fn default() -> EndgameConfig {
+        EndgameConfig {
+            max_deck_size: 1,
+            max_deals: 2000,
+            max_nodes: 50000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct EndgameSolution {
+    pub action: Action,
+    // The mean reward over the deals, under the solver's objective.
+    pub expected_reward: f64,
+    pub deals: usize,
+    pub nodes: usize,
+}
+
+pub struct EndgameSolver {
+    config: EndgameConfig,
+    objective: Objective,
+    deadline: Option<Instant>,
+    prior: HeuristicPrior,
+    // The reward each fully dealt position leads to, and the action chosen
+    // in each view with its total reward over the view's deals. A view
+    // determines its set of deals, so both are keyed by the exact state.
+    values: HashMap<CompactState, f64>,
+    choices: HashMap<CompactState, (Action, f64, usize)>,
+    nodes: usize,
+    aborted: bool,
+}
+
+impl EndgameSolver {
+    pub fn new(config: EndgameConfig, objective: Objective) -> EndgameSolver {
+        EndgameSolver {
+            config,
+            objective,
+            deadline: None,
+            prior: HeuristicPrior::default(),
+            values: HashMap::new(),
+            choices: HashMap::new(),
+            nodes: 0,
+            aborted: false,
+        }
+    }
+
+    // Give up once this time has passed.
+    pub fn set_deadline(&mut self, deadline: Instant) {
+        self.deadline = Some(deadline);
+    }
+
+    // Solves the position seen in a player's view, or returns None if it is
+    // outside the configured limits.
+    pub fn solve(&mut self, view: &GameState) -> Option<EndgameSolution> {
+        if view.deck_size > self.config.max_deck_size {
+            return None;
+        }
+        self.values.clear();
+        self.choices.clear();
+        self.nodes = 0;
+        self.aborted = false;
+
+        let choice = self.choose(&CompactState::from(view));
+        if self.aborted {
+            return None;
+        }
+        let (action, total, deals) = choice?;
+        Some(EndgameSolution {
+            action,
+            expected_reward: total / deals as f64,
+            deals,
+            nodes: self.nodes,
+        })
+    }
+
+    fn visit(&mut self) {
+        self.nodes += 1;
+        if self.nodes >= self.config.max_nodes || self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            self.aborted = true;
+        }
+    }
+
+    // The action the player to act chooses in their view, with its total
+    // reward over the deals consistent with the view and their number.
+    fn choose(&mut self, view: &CompactState) -> Option<(Action, f64, usize)> {
+        if let Some(&choice) = self.choices.get(view) {
+            return Some(choice);
+        }
+        self.visit();
+        if self.aborted {
+            return None;
+        }
+        let deals = match consistent_deals(view, self.config.max_deals) {
+            Some(deals) => deals,
+            None => {
+                self.aborted = true;
+                return None;
+            },
+        };
+
+        // Every objective grows with the score, so bounding the score bounds
+        // the reward. An action is abandoned once even the bounds of its
+        // remaining deals could not lift it above the best so far.
+        let bounds: Vec<f64> = deals.iter()
+            .map(|deal| self.objective.reward(compact_score_bound(deal).score as f64, false))
+            .collect();
+        let max_total: f64 = bounds.iter().sum();
+        let mut best: (f64, Option<Action>) = (f64::NEG_INFINITY, None);
+        for action in self.candidate_actions(view) {
+            let mut total = 0.0;
+            let mut remaining = max_total;
+            let mut pruned = false;
+            for (deal, &bound) in deals.iter().zip(bounds.iter()) {
+                total += self.value_after(deal, action);
+                remaining -= bound;
+                if self.aborted {
+                    return None;
+                }
+                if total + remaining <= best.0 {
+                    pruned = true;
+                    break;
+                }
+            }
+            if !pruned && total > best.0 {
+                best = (total, Some(action));
+            }
+            if best.0 >= max_total {
+                break;
+            }
+        }
+
+        let choice = (best.1?, best.0, deals.len());
+        self.choices.insert(*view, choice);
+        Some(choice)
+    }
+
+    // The reward a fully dealt position leads to when everyone chooses from
+    // their own view.
+    fn value(&mut self, deal: &CompactState) -> f64 {
+        if let Some(&value) = self.values.get(deal) {
+            return value;
+        }
+        self.visit();
+        let view = hide_from(deal, deal.current_player());
+        let value = match self.choose(&view) {
+            Some((action, _, _)) => self.value_after(deal, action),
+            None => return 0.0,
+        };
+        if !self.aborted {
+            self.values.insert(*deal, value);
+        }
+        value
+    }
+
+    fn value_after(&mut self, deal: &CompactState, action: Action) -> f64 {
+        let mut child = *deal;
+        match child.act(action) {
+            CompactResult::Acted => self.value(&child),
+            CompactResult::Finished(score) => self.objective.reward(score as f64, child.strikes() == 3),
+            CompactResult::Illegal(_) | CompactResult::Error(_) => {
+                panic!("Actions legal in a view must be legal in all of its deals");
+            },
+        }
+    }
+
+    // The legal actions, most promising first. Clues that leave the target
+    // knowing exactly the same are only tried once, and all clues that
+    // reveal nothing new are the same as passing the turn.
+    fn candidate_actions(&self, state: &CompactState) -> Vec<Action> {
+        let mut seen_clues: Vec<Option<(Player, Vec<u16>)>> = Vec::new();
+        let mut actions: Vec<(f64, Action)> = Vec::new();
+        for action in state.legal_actions() {
+            if let Action::Clue(target, _) = action {
+                let mut after = *state;
+                after.act(action);
+                let knowledge = |s: &CompactState| s.hand(target).iter().map(|&c_id| s.knowledge[c_id as usize]).collect::<Vec<u16>>();
+                let outcome = knowledge(&after);
+                let outcome = if outcome == knowledge(state) {
+                    None
+                } else {
+                    Some((target, outcome))
+                };
+                if seen_clues.contains(&outcome) {
+                    continue;
+                }
+                seen_clues.push(outcome);
+            }
+            actions.push((self.prior.weight(state, action), action));
+        }
+        // A stable sort keeps the legal order among equal weights.
+        actions.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+        actions.into_iter().map(|(_, action)| action).collect()
+    }
+}
+
+// What a player sees of a deal: everything but their own hand and the deck.
+fn hide_from(deal: &CompactState, player: Player) -> CompactState {
+    let mut view = *deal;
+    for &c_id in deal.hand(player) {
+        view.cards[c_id as usize] = NO_CARD;
+    }
+    let total_cards = deal.next_card as usize + deal.deck_size as usize;
+    for c_id in deal.next_card as usize..total_cards {
+        view.cards[c_id] = NO_CARD;
+    }
+    view
+}
+
+// Every way to fill in the hidden cards of a view that agrees with the clues
+// and the remaining card counts, or None if there are more than max_deals.
+fn consistent_deals(view: &CompactState, max_deals: usize) -> Option<Vec<CompactState>> {
+    let total_cards = view.next_card as usize + view.deck_size as usize;
+    let mut remaining = [0u8; 25];
+    for code in 0..25u8 {
+        remaining[code as usize] = copies(code);
+    }
+    let mut hidden = Vec::new();
+    for c_id in 0..total_cards {
+        match view.cards[c_id] {
+            NO_CARD => {
+                if (view.played | view.discarded) & (1 << c_id) == 0 {
+                    hidden.push(c_id);
+                }
+            },
+            code => remaining[code as usize] -= 1,
+        }
+    }
+
+    let mut deals = Vec::new();
+    let mut deal = *view;
+    if fill(&mut deal, &hidden, &mut remaining, &mut deals, max_deals) {
+        Some(deals)
+    } else {
+        None
+    }
+}
+
+// Assigns the first hidden card every way possible and recurses, returning
+// false once too many deals are found.
+fn fill(deal: &mut CompactState, hidden: &[usize], remaining: &mut [u8; 25], deals: &mut Vec<CompactState>, max_deals: usize) -> bool {
+    let (&c_id, rest) = match hidden.split_first() {
+        Some(split) => split,
+        None => {
+            deals.push(*deal);
+            return deals.len() <= max_deals;
+        },
+    };
+    for code in 0..25u8 {
+        if remaining[code as usize] == 0 || !knowledge_allows(deal.knowledge[c_id], decode_card(code)) {
+            continue;
+        }
+        remaining[code as usize] -= 1;
+        deal.cards[c_id] = code;
+        let ok = fill(deal, rest, remaining, deals, max_deals);
+        remaining[code as usize] += 1;
+        if !ok {
+            return false;
+        }
+    }
+    deal.cards[c_id] = NO_CARD;
+    true
+}
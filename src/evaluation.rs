@@ -0,0 +1,100 @@
+use std::fmt;
+
+use compact::{encode_card, CompactState};
+use hanabi::{GameState, PLAYERS};
+
+// Static estimates of the final score of a game in progress, used to cut
+// playouts short. Evaluators work on CompactStates so they are cheap to call
+// from rollouts; evaluate_state accepts a full GameState.
+
+pub trait Evaluator: fmt::Debug + Send + Sync {
+    // The estimated final score of a game that has not finished.
+    fn evaluate(&self, state: &CompactState) -> f64;
+
+    fn evaluate_state(&self, state: &GameState) -> f64 {
+        self.evaluate(&CompactState::from(state))
+    }
+}
+
+// The current pile score, plus a fraction of the points that are still
+// reachable. A point is reachable if no critical card it needs has been lost
+// and enough plays are left to play it, so losing critical cards and
+// running out of pace both lower the estimate.
+#[derive(Debug, Clone, Copy)]
+pub struct PaceEvaluator {
+    // The fraction of the reachable points expected to be scored.
+    pub potential_weight: f64,
+    // Subtracted per strike, since a third strike scores nothing.
+    pub strike_penalty: f64,
+}
+
+impl Default for PaceEvaluator {
+    fn default() -> PaceEvaluator {
+        PaceEvaluator {
+            potential_weight: 0.5,
+            strike_penalty: 1.0,
+        }
+    }
+}
+
+impl Evaluator for PaceEvaluator {
+    fn evaluate(&self, state: &CompactState) -> f64 {
+        let score = state.score() as f64;
+        let remaining = (state.max_score() - state.score()) as f64;
+        let reachable = remaining.min(state.plays_left() as f64);
+        let estimate = score + self.potential_weight * reachable - self.strike_penalty * state.strikes() as f64;
+        estimate.max(0.0)
+    }
+}
+
+// Adds to the pace estimate what the team knows: cards whose clues prove
+// them playable are worth nearly as much as playing them, and clue tokens
+// are worth a little since they buy future information. Searches that are
+// only a few moves deep need this to see any value in a clue.
+#[derive(Debug, Clone, Copy)]
+pub struct HeuristicEvaluator {
+    pub pace: PaceEvaluator,
+    // Per distinct card in any hand that its owner knows to be playable.
+    // Playing one gains 1 - pace.potential_weight, which this must stay
+    // below.
+    pub known_playable_weight: f64,
+    // Per clue token available.
+    pub clue_weight: f64,
+}
+
+impl Default for HeuristicEvaluator {
+    fn default() -> HeuristicEvaluator {
+        HeuristicEvaluator {
+            pace: PaceEvaluator::default(),
+            known_playable_weight: 0.3,
+            clue_weight: 0.05,
+        }
+    }
+}
+
+impl Evaluator for HeuristicEvaluator {
+    fn evaluate(&self, state: &CompactState) -> f64 {
+        // Copies of the same card only count once, since playing one makes
+        // the others useless.
+        let mut seen = 0u32;
+        let mut known_playable = 0;
+        for &player in PLAYERS.iter() {
+            for &c_id in state.hand(player) {
+                if !state.known_playable(c_id) {
+                    continue;
+                }
+                match state.card(c_id) {
+                    Some(c) if seen & (1 << encode_card(c)) != 0 => {},
+                    Some(c) => {
+                        seen |= 1 << encode_card(c);
+                        known_playable += 1;
+                    },
+                    None => known_playable += 1,
+                }
+            }
+        }
+        self.pace.evaluate(state)
+            + self.known_playable_weight * known_playable as f64
+            + self.clue_weight * state.clues() as f64
+    }
+}
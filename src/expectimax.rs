@@ -0,0 +1,108 @@
+use std::f64;
+use std::sync::Arc;
+
+use rand::Rng;
+
+use agent::{is_clued, possible_cards, Agent};
+use compact::{CompactResult, CompactState};
+use evaluation::{Evaluator, HeuristicEvaluator};
+use hanabi::{Action, GameState};
+
+// A shallow fixed-depth search with no tree statistics. The hidden cards are
+// a chance node at the root: each of the actor's actions is scored by its
+// average over a fixed number of sampled deals. Within a deal the following
+// moves are searched to a fixed depth, each player taking the move that is
+// best for the team, and positions at the horizon are scored by a static
+// evaluator.
+//
+// A deal shows every card, so a search inside one would let players see
+// their own hands. After the first move, players therefore only play cards
+// their clues prove playable, and only discard their chop, the oldest card
+// no clue has touched, or cards their clues prove dead. Ties go to the
+// earlier legal action, so the agent's choices depend only on the rng.
+
+#[derive(Debug)]
+pub struct ExpectimaxAgent {
+    evaluator: Arc<dyn Evaluator>,
+    depth: usize,
+    samples: usize,
+}
+
+impl Default for ExpectimaxAgent {
+    fn default() -> ExpectimaxAgent {
+        ExpectimaxAgent::new(Arc::new(HeuristicEvaluator::default()))
+    }
+}
+
+impl ExpectimaxAgent {
+    pub fn new(evaluator: Arc<dyn Evaluator>) -> ExpectimaxAgent {
+        ExpectimaxAgent {
+            evaluator,
+            depth: 2,
+            samples: 16,
+        }
+    }
+
+    // The number of moves searched, counting the actor's, at least one.
+    pub fn set_depth(&mut self, depth: usize) {
+        self.depth = depth.max(1);
+    }
+
+    // The number of deals each action is averaged over.
+    pub fn set_samples(&mut self, samples: usize) {
+        self.samples = samples;
+    }
+
+    // The value of taking an action in a fully known state, searching depth
+    // moves in all.
+    fn action_value(&self, state: &CompactState, action: Action, depth: usize) -> f64 {
+        let mut child = *state;
+        match child.act(action) {
+            CompactResult::Acted => self.value(&child, depth - 1),
+            CompactResult::Finished(score) => score as f64,
+            CompactResult::Illegal(_) | CompactResult::Error(_) => {
+                panic!("Expectimax needs every card to be known and legal actions");
+            },
+        }
+    }
+
+    fn value(&self, state: &CompactState, depth: usize) -> f64 {
+        if depth == 0 {
+            return self.evaluator.evaluate(state);
+        }
+        let hand = state.hand(state.current_player());
+        // With every card clued, the oldest card is the chop.
+        let chop = (0..hand.len()).find(|&i| !is_clued(state, hand[i])).unwrap_or(0);
+        state.legal_actions().into_iter()
+            .filter(|&action| match action {
+                Action::Play(i) => state.known_playable(hand[i]),
+                Action::Discard(i) => i == chop || possible_cards(state, hand[i]).all(|c| state.is_dead(c)),
+                Action::Clue(..) => true,
+            })
+            .map(|action| self.action_value(state, action, depth))
+            .fold(f64::NEG_INFINITY, f64::max)
+    }
+}
+
+impl Agent for ExpectimaxAgent {
+    fn choose_action(&self, view: &GameState, mut rng: &mut dyn Rng) -> Action {
+        let root = CompactState::from(view);
+        let actions = root.legal_actions();
+        let mut totals = vec![0.0; actions.len()];
+        for _ in 0..self.samples {
+            let mut world = root;
+            world.determinize(&mut rng);
+            for (total, &action) in totals.iter_mut().zip(actions.iter()) {
+                *total += self.action_value(&world, action, self.depth);
+            }
+        }
+
+        let mut best = 0;
+        for (i, &total) in totals.iter().enumerate() {
+            if total > totals[best] {
+                best = i;
+            }
+        }
+        actions[best]
+    }
+}
@@ -6,8 +6,11 @@ use std::ops::AddAssign;
 use rand::Rng;
 use rand::distributions::{Weighted, WeightedChoice};
 
+use compact::{apply_clue, ALL_KNOWLEDGE};
 use determinization::determinize;
 
+pub const HAND_SIZE: usize = 4;
+
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Player {
     Alice,
@@ -25,8 +28,18 @@ impl Player {
             Player::Dave => Player::Alice,
         }
     }
+
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    pub fn from_index(index: usize) -> Player {
+        PLAYERS[index]
+    }
 }
 
+pub const PLAYERS: [Player; 4] = [Player::Alice, Player::Bob, Player::Cathy, Player::Dave];
+
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Suit {
     Red,
@@ -36,6 +49,8 @@ pub enum Suit {
     Purple,
 }
 
+pub const SUITS: [Suit; 5] = [Suit::Red, Suit::Green, Suit::Blue, Suit::Yellow, Suit::Purple];
+
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Rank {
     One,
@@ -45,6 +60,8 @@ pub enum Rank {
     Five,
 }
 
+pub const RANKS: [Rank; 5] = [Rank::One, Rank::Two, Rank::Three, Rank::Four, Rank::Five];
+
 impl Rank {
     pub fn playable_on(self, pile: Option<Rank>) -> bool {
         match pile {
@@ -92,11 +109,11 @@ pub enum Clue {
 
 impl Clue {
     pub fn matches(&self, card: Card) -> bool {
-        match self {
-            &Clue::Suit(s) => {
+        match *self {
+            Clue::Suit(s) => {
                 s == card.suit
             },
-            &Clue::Rank(r) => {
+            Clue::Rank(r) => {
                 r == card.rank
             },
         }
@@ -117,19 +134,23 @@ impl CardId {
 // with the only difference being which cards are included in card_map.
 #[derive(Debug, Clone)]
 pub struct GameState {
-    card_map: HashMap<CardId, Card>,
-    deck_size: usize,
-    next_card_id: CardId,
-    current_turn: Player,
-    final_turn: Option<Player>,
-    hands: HashMap<Player, Vec<CardId>>,
-    played_cards: Vec<CardId>,
-    discarded_cards: Vec<CardId>,
-    piles: HashMap<Suit, Rank>,
-    clues: u8,
-    strikes: u8,
-    information: HashMap<CardId, Vec<Information>>,
-    action_log: Vec<CompletedAction>,
+    pub(crate) card_map: HashMap<CardId, Card>,
+    pub(crate) deck_size: usize,
+    pub(crate) next_card_id: CardId,
+    pub(crate) current_turn: Player,
+    pub(crate) final_turn: Option<Player>,
+    pub(crate) hands: HashMap<Player, Vec<CardId>>,
+    pub(crate) played_cards: Vec<CardId>,
+    pub(crate) discarded_cards: Vec<CardId>,
+    pub(crate) piles: HashMap<Suit, Rank>,
+    pub(crate) clues: u8,
+    pub(crate) strikes: u8,
+    pub(crate) information: HashMap<CardId, Vec<Information>>,
+    pub(crate) action_log: Vec<CompletedAction>,
+    // Incrementally maintained hashes of card_map and action_log, which
+    // together identify the fingerprint.
+    pub(crate) card_hash: u64,
+    pub(crate) history_hash: u64,
 }
 
 #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
@@ -140,7 +161,7 @@ pub enum Action {
 }
 
 #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
-pub struct Information(Clue, bool);
+pub struct Information(pub Clue, pub bool);
 
 impl Information {
     pub fn consistent_with(self, card: Card) -> bool {
@@ -159,16 +180,64 @@ pub enum CompletedAction {
     Clued(Player, Clue, Vec<CardId>),
 }
 
+impl CompletedAction {
+    pub fn action(&self) -> Action {
+        match *self {
+            CompletedAction::Discarded(i, _) => Action::Discard(i),
+            CompletedAction::Played(i, _) => Action::Play(i),
+            CompletedAction::Clued(target, clue, _) => Action::Clue(target, clue),
+        }
+    }
+}
+
 // Game states are not hashable, but each one is uniquely determined by the
 // sequence of actions taken (and the information revealed by them), as well
 // as the set of visible cards.
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
 pub struct Fingerprint {
-    known_cards: Vec<(CardId, Card)>,
-    actions: Vec<CompletedAction>,
+    pub(crate) known_cards: Vec<(CardId, Card)>,
+    pub(crate) actions: Vec<CompletedAction>,
 }
 
-#[derive(Debug, Clone)]
+// The 64-bit finalizer from splitmix64, used to build Zobrist-style keys
+// without a table of random numbers.
+fn mix(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+fn card_index(card: Card) -> u64 {
+    card.suit as u64 * 5 + card.rank as u64
+}
+
+// Known cards contribute to the fingerprint hash independently, so the key
+// of a set of cards is the XOR of the keys of its members.
+pub(crate) fn card_key(c_id: CardId, card: Card) -> u64 {
+    let CardId(id) = c_id;
+    mix(((id as u64) << 8) | card_index(card))
+}
+
+// Actions are chained, so the order of the action log matters.
+fn extend_history_hash(hash: u64, action: &CompletedAction) -> u64 {
+    let key = match *action {
+        CompletedAction::Discarded(i, c) => mix((1 << 16) | ((i as u64) << 8) | card_index(c)),
+        CompletedAction::Played(i, c) => mix((2 << 16) | ((i as u64) << 8) | card_index(c)),
+        CompletedAction::Clued(target, clue, ref matching) => {
+            let clue_index = match clue {
+                Clue::Suit(s) => s as u64,
+                Clue::Rank(r) => 5 + r as u64,
+            };
+            matching.iter().fold(mix((3 << 16) | ((target as u64) << 8) | clue_index), |key, &CardId(id)| {
+                mix(key ^ id as u64)
+            })
+        },
+    };
+    mix(hash ^ key)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum IllegalAction {
     NoSuchCard,
     NoMatchingCards,
@@ -177,7 +246,7 @@ pub enum IllegalAction {
     TooManyClues,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ActionError {
     UnknownCard,
 }
@@ -193,8 +262,10 @@ pub enum ActionResult {
 impl GameState {
     pub fn initial(deck_order: &[Card]) -> GameState {
         let mut card_map = HashMap::new();
+        let mut card_hash = 0;
         for (i, c) in deck_order.iter().cloned().enumerate() {
             card_map.insert(CardId(i), c);
+            card_hash ^= card_key(CardId(i), c);
         }
 
         GameState {
@@ -216,34 +287,33 @@ impl GameState {
             strikes: 0,
             information: HashMap::new(),
             action_log: Vec::new(),
+            card_hash,
+            history_hash: 0,
         }
     }
 
     pub fn reduce_to_player_view(&mut self, player: Player) {
         let mut viewed_card_map = HashMap::new();
-        for (&p, h) in self.hands.iter() {
-            if p != player {
-                for c_id in h.iter() {
-                    if let Some(&c) = self.card_map.get(c_id) {
-                        viewed_card_map.insert(*c_id, c);
-                    }
-                }
-            }
-        }
-
-        for c_id in self.played_cards.iter() {
-            if let Some(&c) = self.card_map.get(c_id) {
-                viewed_card_map.insert(*c_id, c);
-            }
-        }
-
-        for c_id in self.discarded_cards.iter() {
-            if let Some(&c) = self.card_map.get(c_id) {
-                viewed_card_map.insert(*c_id, c);
+        let mut viewed_hash = 0;
+        for c_id in self.visible_cards(player) {
+            if let Some(&c) = self.card_map.get(&c_id) {
+                viewed_card_map.insert(c_id, c);
+                viewed_hash ^= card_key(c_id, c);
             }
         }
 
         self.card_map = viewed_card_map;
+        self.card_hash = viewed_hash;
+    }
+
+    // The ids of the cards that the given player can see: everyone else's
+    // hands, and the played and discarded cards.
+    pub(crate) fn visible_cards<'a>(&'a self, player: Player) -> impl Iterator<Item = CardId> + 'a {
+        self.hands.iter()
+            .filter(move |&(&p, _)| p != player)
+            .flat_map(|(_, h)| h.iter().cloned())
+            .chain(self.played_cards.iter().cloned())
+            .chain(self.discarded_cards.iter().cloned())
     }
 
     pub fn reduce_to_current_view(&mut self) {
@@ -253,25 +323,11 @@ impl GameState {
 
     pub fn player_view(&self, player: Player) -> GameState {
         let mut viewed_card_map = HashMap::new();
-        for (&p, h) in self.hands.iter() {
-            if p != player {
-                for c_id in h.iter() {
-                    if let Some(&c) = self.card_map.get(c_id) {
-                        viewed_card_map.insert(*c_id, c);
-                    }
-                }
-            }
-        }
-
-        for c_id in self.played_cards.iter() {
-            if let Some(&c) = self.card_map.get(c_id) {
-                viewed_card_map.insert(*c_id, c);
-            }
-        }
-
-        for c_id in self.discarded_cards.iter() {
-            if let Some(&c) = self.card_map.get(c_id) {
-                viewed_card_map.insert(*c_id, c);
+        let mut viewed_hash = 0;
+        for c_id in self.visible_cards(player) {
+            if let Some(&c) = self.card_map.get(&c_id) {
+                viewed_card_map.insert(c_id, c);
+                viewed_hash ^= card_key(c_id, c);
             }
         }
 
@@ -289,6 +345,8 @@ impl GameState {
             strikes: self.strikes,
             information: self.information.clone(),
             action_log: self.action_log.clone(),
+            card_hash: viewed_hash,
+            history_hash: self.history_hash,
         }
     }
 
@@ -342,7 +400,7 @@ impl GameState {
 
                 let completed_action = CompletedAction::Discarded(i, c);
 
-                self.action_log.push(completed_action.clone());
+                self.log_action(completed_action.clone());
                 ActionResult::Acted(completed_action)
             },
             Action::Play(i) => {
@@ -402,7 +460,7 @@ impl GameState {
 
                 self.current_turn = current_player.next();
                 let completed_action = CompletedAction::Played(i, c);
-                self.action_log.push(completed_action.clone());
+                self.log_action(completed_action.clone());
 
                 ActionResult::Acted(completed_action)
             },
@@ -423,7 +481,7 @@ impl GameState {
                     if clue.matches(c) {
                         matching_cards.push(c_id);
                     }
-                    let info_vec: &mut Vec<Information> = self.information.entry(c_id).or_insert_with(Vec::new);
+                    let info_vec: &mut Vec<Information> = self.information.entry(c_id).or_default();
                     info_vec.push(Information(clue, clue.matches(c)));
                 }
 
@@ -450,12 +508,17 @@ impl GameState {
                 self.current_turn = current_player.next();
 
                 let completed_action = CompletedAction::Clued(target, clue, matching_cards);
-                self.action_log.push(completed_action.clone());
+                self.log_action(completed_action.clone());
                 ActionResult::Acted(completed_action)
             },
         }
     }
 
+    fn log_action(&mut self, completed_action: CompletedAction) {
+        self.history_hash = extend_history_hash(self.history_hash, &completed_action);
+        self.action_log.push(completed_action);
+    }
+
     pub fn unknown_cards(&self) -> Vec<CardId> {
         let mut unknowns = Vec::new();
 
@@ -482,6 +545,34 @@ impl GameState {
         self.current_turn
     }
 
+    pub fn action_log(&self) -> &[CompletedAction] {
+        &self.action_log
+    }
+
+    pub fn hand(&self, player: Player) -> &[CardId] {
+        &self.hands[&player]
+    }
+
+    pub fn known_card(&self, c_id: CardId) -> Option<Card> {
+        self.card_map.get(&c_id).cloned()
+    }
+
+    pub fn card_information(&self, c_id: CardId) -> &[Information] {
+        match self.information.get(&c_id) {
+            Some(info) => info,
+            None => &[],
+        }
+    }
+
+    // Whether the given card is consistent with everything that has been
+    // revealed about the card with the given id.
+    pub fn card_consistent(&self, c_id: CardId, card: Card) -> bool {
+        if let Some(&known) = self.card_map.get(&c_id) {
+            return known == card;
+        }
+        self.card_information(c_id).iter().all(|info| info.consistent_with(card))
+    }
+
     pub fn remaining_card_counts(&self) -> HashMap<Card, usize> {
         let mut seen_counts: HashMap<Card, usize> = HashMap::new();
         for (_, &c) in self.card_map.iter() {
@@ -506,6 +597,16 @@ impl GameState {
     pub fn determinize<R>(&mut self, priors: &HashMap<CardId, Vec<Weighted<Card>>>, rng: &mut R)
         where
         R: Rng,
+    {
+        let assignment = self.sample_assignment(priors, rng);
+        self.assign_cards(&assignment);
+    }
+
+    // Samples identities for all of the unknown cards without modifying the
+    // state. The result can later be applied with assign_cards.
+    pub fn sample_assignment<R>(&self, priors: &HashMap<CardId, Vec<Weighted<Card>>>, rng: &mut R) -> HashMap<CardId, Card>
+        where
+        R: Rng,
     {
         let unknowns = self.unknown_cards();
         let remaining_counts = self.remaining_card_counts();
@@ -524,7 +625,7 @@ impl GameState {
                             }
                         }
                         if ok {
-                            restricted_prior.push(w.clone());
+                            restricted_prior.push(*w);
                         }
                     }
                     restricted_priors.insert(c_id, restricted_prior);
@@ -551,9 +652,7 @@ impl GameState {
             }
         }
         for (&c_id, existing_prior) in priors.iter() {
-            if !restricted_priors.contains_key(&c_id) {
-                restricted_priors.insert(c_id, existing_prior.clone());
-            }
+            restricted_priors.entry(c_id).or_insert_with(|| existing_prior.clone());
         }
 
         let mut new_priors: HashMap<CardId, WeightedChoice<Card>> = HashMap::new();
@@ -562,15 +661,20 @@ impl GameState {
             new_priors.insert(c_id, dist);
         }
 
-        let assignment = determinize(
+        determinize(
             &unknowns,
             &remaining_counts,
             &new_priors,
             rng,
-        );
+        )
+    }
 
+    pub fn assign_cards(&mut self, assignment: &HashMap<CardId, Card>) {
         for (&c_id, &c) in assignment.iter() {
-            self.card_map.insert(c_id, c);
+            if let Some(old) = self.card_map.insert(c_id, c) {
+                self.card_hash ^= card_key(c_id, old);
+            }
+            self.card_hash ^= card_key(c_id, c);
         }
     }
 
@@ -587,6 +691,23 @@ impl GameState {
         }
     }
 
+    // A hash of the fingerprint, maintained as the state changes so that it
+    // costs nothing to compute. Equal fingerprints always have equal hashes.
+    pub fn fingerprint_hash(&self) -> u64 {
+        self.card_hash ^ self.history_hash
+    }
+
+    // The fingerprint hash of player_view(player), without building the view.
+    pub fn player_view_hash(&self, player: Player) -> u64 {
+        let mut hash = self.history_hash;
+        for c_id in self.visible_cards(player) {
+            if let Some(&c) = self.card_map.get(&c_id) {
+                hash ^= card_key(c_id, c);
+            }
+        }
+        hash
+    }
+
     pub fn current_view(&self) -> GameState {
         self.player_view(self.current_turn)
     }
@@ -649,4 +770,50 @@ impl GameState {
 
         actions
     }
+
+    // Like legal_actions, but if merge_clues is set, only the first action
+    // of each class from action_classes is included.
+    pub fn legal_actions_with(&self, merge_clues: bool) -> Vec<Action> {
+        if merge_clues {
+            self.action_classes().into_iter().map(|class| class[0]).collect()
+        } else {
+            self.legal_actions()
+        }
+    }
+
+    // Partitions the legal actions into classes of clues to the same player
+    // that leave the target knowing exactly the same about each of their
+    // cards. A rank clue and a suit clue that touch the same cards usually
+    // tell the target different things, so they are rarely merged.
+    // Plays and discards are in classes of their own. Classes and their
+    // members are in legal_actions order.
+    pub fn action_classes(&self) -> Vec<Vec<Action>> {
+        let mut classes: Vec<Vec<Action>> = Vec::new();
+        let mut outcomes: Vec<Option<(Player, Vec<u16>)>> = Vec::new();
+        for action in self.legal_actions() {
+            let key = match action {
+                Action::Clue(target, clue) => {
+                    let knowledge: Vec<u16> = self.hands[&target].iter()
+                        .map(|c_id| {
+                            let matches = self.card_map.get(c_id).is_some_and(|&c| clue.matches(c));
+                            let knowledge = self.information.get(c_id).map_or(ALL_KNOWLEDGE, |infos| {
+                                infos.iter().fold(ALL_KNOWLEDGE, |k, &Information(clue, matches)| apply_clue(k, clue, matches))
+                            });
+                            apply_clue(knowledge, clue, matches)
+                        })
+                        .collect();
+                    Some((target, knowledge))
+                },
+                _ => None,
+            };
+            match outcomes.iter().position(|k| k.is_some() && *k == key) {
+                Some(index) => classes[index].push(action),
+                None => {
+                    classes.push(vec![action]);
+                    outcomes.push(key);
+                },
+            }
+        }
+        classes
+    }
 }
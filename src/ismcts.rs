@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+
+use rand::Rng;
+use rand::distributions::{IndependentSample, Range};
+
+use basic_mcts::{Node, Selection, SelectionRule};
+use hanabi::{Action, ActionResult, GameState, Player};
+use symmetry::{canonical_form, Symmetry};
+
+// Multi-observer information set MCTS.
+// Every player has a separate tree whose nodes are that player's information
+// sets, so a player's statistics only ever depend on what that player could
+// see. Each playout fixes a single determinization of the root and walks all
+// of the trees through it, and every tree is updated with the same result.
+
+// The nodes visited in a playout: the tree owner, the hash of the owner's
+// information set, and the action taken there (in the canonical frame, if
+// canonicalizing).
+pub type IsmctsUpdates = Vec<(Player, u64, Action)>;
+
+pub struct IsmctsState {
+    root: GameState,
+    exploration: f64,
+    canonicalize: bool,
+    trees: HashMap<Player, HashMap<u64, Node>>,
+}
+
+impl IsmctsState {
+    pub fn new(root: GameState, exploration: f64) -> IsmctsState {
+        IsmctsState {
+            root,
+            exploration,
+            canonicalize: false,
+            trees: HashMap::new(),
+        }
+    }
+
+    // Key information sets by their canonical form under suit relabeling,
+    // so that symmetric information sets share a node. Nodes then store
+    // actions in the canonical frame.
+    pub fn set_canonicalize(&mut self, canonicalize: bool) {
+        self.canonicalize = canonicalize;
+    }
+
+    // The key of a player's information set, and the symmetry that takes
+    // actions there into the frame of the key.
+    fn information_set(&self, state: &GameState, player: Player) -> (u64, Symmetry) {
+        if self.canonicalize {
+            let form = canonical_form(state, player);
+            (form.hash(), form.symmetry)
+        } else {
+            (state.player_view_hash(player), Symmetry::identity())
+        }
+    }
+
+    pub fn choose_action<R: Rng>(&self, rng: &mut R) -> Action {
+        let player = self.root.current_player();
+        let (hash, symmetry) = self.information_set(&self.root, player);
+        let legal_actions: Vec<Action> = self.root.legal_actions().iter().map(|&a| symmetry.action(a)).collect();
+        let unexplored = Node::new();
+        let root_node = self.trees.get(&player).and_then(|tree| tree.get(&hash)).unwrap_or(&unexplored);
+        symmetry.inverse().action(root_node.best_action(&legal_actions, rng))
+    }
+
+    pub fn run_playout<R: Rng>(&self, rng: &mut R) -> (IsmctsUpdates, f64) {
+        let mut world = self.root.clone();
+        world.determinize(&HashMap::new(), rng);
+        self.run_playout_from(world, rng)
+    }
+
+    // Runs a playout in a caller-supplied determinization of the root, such
+    // as a particle drawn from a ParticleFilter. Each tree is descended until
+    // it reaches an information set it has not seen, which is added to that
+    // tree; the owner's later moves in the playout are random.
+    pub fn run_playout_from<R: Rng>(&self, world: GameState, rng: &mut R) -> (IsmctsUpdates, f64) {
+        let mut updates: IsmctsUpdates = Vec::new();
+        let mut expanded: Vec<Player> = Vec::new();
+        let mut current_state = world;
+        let result = loop {
+            let player = current_state.current_player();
+            let legal_actions = current_state.legal_actions();
+
+            let action = if expanded.contains(&player) {
+                let index = Range::new(0, legal_actions.len()).ind_sample(rng);
+                legal_actions[index]
+            } else {
+                let (hash, symmetry) = self.information_set(&current_state, player);
+                let legal_actions: Vec<Action> = legal_actions.iter().map(|&a| symmetry.action(a)).collect();
+                let action = match self.trees.get(&player).and_then(|tree| tree.get(&hash)) {
+                    Some(node) => {
+                        let selection = Selection {
+                            rule: SelectionRule::Ucb1,
+                            exploration: self.exploration,
+                            reward_offset: 0.0,
+                            reward_scale: 1.0,
+                            priors: &[],
+                            rave_equivalence: None,
+                            loss_reward: 0.0,
+                        };
+                        node.select(&legal_actions, &selection, rng)
+                    },
+                    None => {
+                        expanded.push(player);
+                        let index = Range::new(0, legal_actions.len()).ind_sample(rng);
+                        legal_actions[index]
+                    },
+                };
+                updates.push((player, hash, action));
+                symmetry.inverse().action(action)
+            };
+
+            match current_state.act(action) {
+                ActionResult::Acted(_) => {},
+                ActionResult::Illegal(_) => {
+                    panic!("ISMCTS tried to play an illegal action!");
+                },
+                ActionResult::Error(_) => {
+                    panic!("ISMCTS encountered an action error!");
+                },
+                ActionResult::Finished(score) => {
+                    break score as f64;
+                },
+            }
+        };
+
+        (updates, result)
+    }
+
+    pub fn update(&mut self, updates: IsmctsUpdates, result: f64) {
+        for (player, hash, action) in updates {
+            let tree = self.trees.entry(player).or_default();
+            let node = tree.entry(hash).or_insert_with(Node::new);
+            node.add_sample(action, result);
+        }
+    }
+}
@@ -1,9 +1,24 @@
 extern crate rand;
+extern crate rayon;
 
 pub mod hanabi;
 pub mod determinization;
+pub mod particle_filter;
+pub mod compact;
+pub mod rollout;
+pub mod evaluation;
+pub mod prior;
+pub mod symmetry;
+pub mod solver;
+pub mod endgame;
+pub mod agent;
+pub mod sparta;
+pub mod expectimax;
+pub mod conventions;
+pub mod hat;
 
 pub mod basic_mcts;
+pub mod ismcts;
 
 #[cfg(test)]
 mod test;
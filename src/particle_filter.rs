@@ -0,0 +1,288 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use rand::{Rng, XorShiftRng};
+use rand::distributions::{IndependentSample, Range};
+
+use agent::Agent;
+use hanabi::{Card, CardId, CompletedAction, GameState, Player};
+
+// A particle filter over the cards that one player cannot see.
+// Each particle is a complete deal of the unknown cards (the observer's hand
+// and the deck) together with a weight. Rather than sampling a fresh
+// determinization every time one is needed, the particles are carried from
+// turn to turn and reweighted as actions and clues are observed.
+
+#[derive(Debug, Clone)]
+pub struct Particle {
+    pub assignment: HashMap<CardId, Card>,
+    pub weight: f64,
+}
+
+// The relative probability that the acting player would have taken an action,
+// given a fully determined world. This is where inference about partners'
+// behavior plugs into the filter.
+pub trait ActionLikelihood {
+    fn likelihood(&self, world: &GameState, action: &CompletedAction) -> f64;
+}
+
+// Treats every legal action as equally likely, so only consistency with the
+// revealed information matters.
+pub struct UniformLikelihood;
+
+impl ActionLikelihood for UniformLikelihood {
+    fn likelihood(&self, _world: &GameState, _action: &CompletedAction) -> f64 {
+        1.0
+    }
+}
+
+// Assumes the acting player follows a blueprint agent, but makes mistakes: an
+// action the blueprint would not take in a world keeps the given fraction of
+// that world's weight, so that a partner who strays from the blueprint does
+// not rule out every particle.
+pub struct BlueprintLikelihood {
+    agent: Arc<dyn Agent>,
+    mistake_weight: f64,
+}
+
+impl BlueprintLikelihood {
+    pub fn new(agent: Arc<dyn Agent>, mistake_weight: f64) -> BlueprintLikelihood {
+        BlueprintLikelihood {
+            agent,
+            mistake_weight,
+        }
+    }
+}
+
+impl ActionLikelihood for BlueprintLikelihood {
+    fn likelihood(&self, world: &GameState, action: &CompletedAction) -> f64 {
+        // A fixed rng keeps the weights reproducible for blueprints that
+        // randomize.
+        let mut rng = XorShiftRng::new_unseeded();
+        let view = world.current_view();
+        if self.agent.choose_action(&view, &mut rng) == action.action() {
+            1.0
+        } else {
+            self.mistake_weight
+        }
+    }
+}
+
+pub struct ParticleFilter {
+    observer: Player,
+    view: GameState,
+    particles: Vec<Particle>,
+    resample_threshold: f64,
+    rejuvenation_moves: usize,
+}
+
+impl ParticleFilter {
+    pub fn new<R: Rng>(view: GameState, observer: Player, num_particles: usize, rng: &mut R) -> ParticleFilter {
+        let mut filter = ParticleFilter {
+            observer,
+            view,
+            particles: Vec::with_capacity(num_particles),
+            resample_threshold: 0.5,
+            rejuvenation_moves: 4,
+        };
+        filter.reinitialize(num_particles, rng);
+        filter
+    }
+
+    // Resampling happens when the effective sample size falls below this
+    // fraction of the number of particles.
+    pub fn set_resample_threshold(&mut self, threshold: f64) {
+        self.resample_threshold = threshold;
+    }
+
+    // The number of swap moves applied to each particle after resampling.
+    pub fn set_rejuvenation_moves(&mut self, moves: usize) {
+        self.rejuvenation_moves = moves;
+    }
+
+    pub fn observer(&self) -> Player {
+        self.observer
+    }
+
+    pub fn view(&self) -> &GameState {
+        &self.view
+    }
+
+    pub fn particles(&self) -> &[Particle] {
+        &self.particles
+    }
+
+    pub fn effective_sample_size(&self) -> f64 {
+        let mut total = 0.0;
+        let mut total_squared = 0.0;
+        for p in self.particles.iter() {
+            total += p.weight;
+            total_squared += p.weight * p.weight;
+        }
+        if total_squared == 0.0 {
+            0.0
+        } else {
+            total * total / total_squared
+        }
+    }
+
+    // Updates the filter after an action, given the observer's view of the
+    // state after the action was taken.
+    pub fn observe<R: Rng>(&mut self, new_view: GameState, rng: &mut R) {
+        for p in self.particles.iter_mut() {
+            restrict_particle(p, &new_view);
+        }
+        self.view = new_view;
+        self.normalize(rng);
+    }
+
+    // Like observe, but additionally weights each particle by how likely the
+    // acting player was to take the action in that particle's world.
+    pub fn observe_with<L, R>(&mut self, action: &CompletedAction, new_view: GameState, likelihood: &L, rng: &mut R)
+        where
+        L: ActionLikelihood + ?Sized,
+        R: Rng,
+    {
+        for p in self.particles.iter_mut() {
+            if p.weight == 0.0 {
+                continue;
+            }
+            let world = particle_world(&self.view, p);
+            p.weight *= likelihood.likelihood(&world, action);
+            restrict_particle(p, &new_view);
+        }
+        self.view = new_view;
+        self.normalize(rng);
+    }
+
+    // Draws a particle according to the weights and returns the full
+    // determinized state it describes.
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> GameState {
+        let total: f64 = self.particles.iter().map(|p| p.weight).sum();
+        let mut target = Range::new(0.0, total).ind_sample(rng);
+        for p in self.particles.iter() {
+            if target < p.weight {
+                return self.world(p);
+            }
+            target -= p.weight;
+        }
+        self.world(self.particles.last().unwrap())
+    }
+
+    fn world(&self, particle: &Particle) -> GameState {
+        particle_world(&self.view, particle)
+    }
+
+    fn reinitialize<R: Rng>(&mut self, num_particles: usize, rng: &mut R) {
+        self.particles.clear();
+        let weight = 1.0 / num_particles as f64;
+        for _ in 0..num_particles {
+            self.particles.push(Particle {
+                assignment: self.view.sample_assignment(&HashMap::new(), rng),
+                weight,
+            });
+        }
+    }
+
+    fn normalize<R: Rng>(&mut self, rng: &mut R) {
+        let num_particles = self.particles.len();
+        let total: f64 = self.particles.iter().map(|p| p.weight).sum();
+        if total == 0.0 {
+            // Every particle has been ruled out, so start over from the
+            // current view.
+            self.reinitialize(num_particles, rng);
+            return;
+        }
+        for p in self.particles.iter_mut() {
+            p.weight /= total;
+        }
+
+        if self.effective_sample_size() < self.resample_threshold * num_particles as f64 {
+            self.resample(rng);
+            self.rejuvenate(rng);
+        }
+    }
+
+    // Systematic resampling: afterwards every particle has equal weight.
+    fn resample<R: Rng>(&mut self, rng: &mut R) {
+        let num_particles = self.particles.len();
+        let step = 1.0 / num_particles as f64;
+        let mut position = Range::new(0.0, step).ind_sample(rng);
+        let mut cumulative = 0.0;
+        let mut resampled = Vec::with_capacity(num_particles);
+        for p in self.particles.iter() {
+            cumulative += p.weight;
+            while position < cumulative && resampled.len() < num_particles {
+                resampled.push(Particle {
+                    assignment: p.assignment.clone(),
+                    weight: step,
+                });
+                position += step;
+            }
+        }
+        while resampled.len() < num_particles {
+            // Only reachable through rounding error.
+            let last = self.particles.iter().rev().find(|p| p.weight > 0.0).unwrap();
+            resampled.push(Particle {
+                assignment: last.assignment.clone(),
+                weight: step,
+            });
+        }
+        self.particles = resampled;
+    }
+
+    // Resampling leaves many identical particles. Diversify them by swapping
+    // the identities of pairs of unknown cards where both cards remain
+    // consistent with the clues they have received. Swaps preserve the
+    // multiset of unseen cards, but do not account for action likelihoods.
+    fn rejuvenate<R: Rng>(&mut self, rng: &mut R) {
+        if self.rejuvenation_moves == 0 {
+            return;
+        }
+        let mut ids: Vec<CardId> = self.view.unknown_cards();
+        ids.sort();
+        if ids.len() < 2 {
+            return;
+        }
+        let range = Range::new(0, ids.len());
+        for p in self.particles.iter_mut() {
+            for _ in 0..self.rejuvenation_moves {
+                let a = ids[range.ind_sample(rng)];
+                let b = ids[range.ind_sample(rng)];
+                let card_a = p.assignment[&a];
+                let card_b = p.assignment[&b];
+                if card_a != card_b && self.view.card_consistent(a, card_b) && self.view.card_consistent(b, card_a) {
+                    p.assignment.insert(a, card_b);
+                    p.assignment.insert(b, card_a);
+                }
+            }
+        }
+    }
+}
+
+fn particle_world(view: &GameState, particle: &Particle) -> GameState {
+    let mut world = view.clone();
+    world.assign_cards(&particle.assignment);
+    world
+}
+
+// Zeroes the weight of a particle that contradicts the view, and otherwise
+// drops the cards that the view now knows.
+fn restrict_particle(particle: &mut Particle, view: &GameState) {
+    if particle.weight == 0.0 {
+        return;
+    }
+    let mut revealed = Vec::new();
+    for (&c_id, &c) in particle.assignment.iter() {
+        if !view.card_consistent(c_id, c) {
+            particle.weight = 0.0;
+            return;
+        }
+        if view.known_card(c_id).is_some() {
+            revealed.push(c_id);
+        }
+    }
+    for c_id in revealed {
+        particle.assignment.remove(&c_id);
+    }
+}
@@ -0,0 +1,89 @@
+use std::fmt;
+
+use compact::CompactState;
+use hanabi::{Action, GameState};
+
+// Prior probabilities over the legal actions in a state, for search rules
+// that favor some moves before they have been sampled.
+
+pub trait ActionPrior: fmt::Debug + Send + Sync {
+    // One non-negative weight per legal action, in the same order, summing
+    // to one.
+    fn priors(&self, state: &GameState, legal_actions: &[Action]) -> Vec<f64>;
+}
+
+// Every legal action is equally likely.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UniformPrior;
+
+impl ActionPrior for UniformPrior {
+    fn priors(&self, _state: &GameState, legal_actions: &[Action]) -> Vec<f64> {
+        vec![1.0 / legal_actions.len() as f64; legal_actions.len()]
+    }
+}
+
+// Weights actions by how a sensible player would rank them. The actor's own
+// cards are judged only by the clues on them, while partners' cards are
+// visible and judged by their identity.
+#[derive(Debug, Clone, Copy)]
+pub struct HeuristicPrior {
+    pub known_play: f64,
+    pub other_play: f64,
+    // A clue that touches a playable card the target does not know about.
+    pub play_clue: f64,
+    pub other_clue: f64,
+    pub discard: f64,
+}
+
+impl Default for HeuristicPrior {
+    fn default() -> HeuristicPrior {
+        HeuristicPrior {
+            known_play: 8.0,
+            other_play: 0.25,
+            play_clue: 4.0,
+            other_clue: 1.0,
+            discard: 1.0,
+        }
+    }
+}
+
+impl HeuristicPrior {
+    pub fn weight(&self, state: &CompactState, action: Action) -> f64 {
+        match action {
+            Action::Play(i) => {
+                let c_id = state.hand(state.current_player())[i];
+                if state.known_playable(c_id) {
+                    self.known_play
+                } else {
+                    self.other_play
+                }
+            },
+            Action::Discard(_) => self.discard,
+            Action::Clue(target, clue) => {
+                let touches_playable = state.hand(target).iter().any(|&c_id| {
+                    match state.card(c_id) {
+                        Some(c) => clue.matches(c) && state.is_playable(c) && !state.known_playable(c_id),
+                        None => false,
+                    }
+                });
+                if touches_playable {
+                    self.play_clue
+                } else {
+                    self.other_clue
+                }
+            },
+        }
+    }
+}
+
+impl ActionPrior for HeuristicPrior {
+    fn priors(&self, state: &GameState, legal_actions: &[Action]) -> Vec<f64> {
+        let compact = CompactState::from(state);
+        let mut weights: Vec<f64> = legal_actions.iter().map(|&a| self.weight(&compact, a)).collect();
+        let total: f64 = weights.iter().sum();
+        for w in weights.iter_mut() {
+            *w /= total;
+        }
+        weights
+    }
+}
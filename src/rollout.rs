@@ -0,0 +1,150 @@
+use std::fmt;
+
+use rand::Rng;
+use rand::distributions::{IndependentSample, Range};
+
+use agent::{is_clued, possible_cards};
+use compact::{clue_dimension, CompactResult, CompactState};
+use hanabi::{Action, Clue, Player};
+
+// How moves are chosen once a playout has left the search tree. Policies act
+// on fully determinized CompactStates, so they may look at any card, but the
+// built-in heuristic only uses the actor's own clue knowledge to decide what
+// to play.
+
+pub trait RolloutPolicy: fmt::Debug + Send + Sync {
+    // Picks one of the legal actions, which is never empty.
+    fn choose_action(&self, state: &CompactState, legal_actions: &[Action], rng: &mut dyn Rng) -> Action;
+}
+
+// Plays the game with the given policy and returns the final score, or None
+// if max_moves moves were played first, in which case the state is left at
+// the cutoff. If a trace is given, each move is appended to it along with
+// the player who made it.
+pub fn rollout<R: Rng>(
+    state: &mut CompactState,
+    policy: &dyn RolloutPolicy,
+    max_moves: Option<usize>,
+    mut trace: Option<&mut Vec<(Player, Action)>>,
+    rng: &mut R,
+) -> Option<i8> {
+    let mut actions = Vec::with_capacity(40);
+    let mut moves = 0;
+    loop {
+        if max_moves == Some(moves) {
+            return None;
+        }
+        moves += 1;
+        state.legal_actions_into(&mut actions);
+        let action = policy.choose_action(state, &actions, rng);
+        if let Some(ref mut trace) = trace {
+            trace.push((state.current_player(), action));
+        }
+        match state.act(action) {
+            CompactResult::Acted => {},
+            CompactResult::Finished(score) => return Some(score),
+            CompactResult::Illegal(_) => panic!("Rollout policy chose an illegal action!"),
+            CompactResult::Error(_) => panic!("Rollouts need every card to be known"),
+        }
+    }
+}
+
+// Picks uniformly among the legal actions.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UniformRollout;
+
+impl RolloutPolicy for UniformRollout {
+    fn choose_action(&self, _state: &CompactState, legal_actions: &[Action], mut rng: &mut dyn Rng) -> Action {
+        legal_actions[Range::new(0, legal_actions.len()).ind_sample(&mut rng)]
+    }
+}
+
+// A simple cooperative strategy, in order of preference:
+//  1. play a card the actor knows is playable,
+//  2. clue a partner about a playable card,
+//  3. discard the oldest card that has no clues or that the clues show is
+//     not critical,
+//  4. anything legal.
+// With probability epsilon it instead acts uniformly at random, which keeps
+// the rollouts from always following the same line.
+#[derive(Debug, Clone, Copy)]
+pub struct HeuristicRollout {
+    pub epsilon: f64,
+}
+
+impl Default for HeuristicRollout {
+    fn default() -> HeuristicRollout {
+        HeuristicRollout {
+            epsilon: 0.1,
+        }
+    }
+}
+
+impl HeuristicRollout {
+    // Judged by the actor's knowledge, not the card itself, which the actor
+    // cannot see.
+    fn safe_to_discard(state: &CompactState, index: usize) -> bool {
+        let c_id = state.hand(state.current_player())[index];
+        !is_clued(state, c_id) || possible_cards(state, c_id).all(|c| !state.is_critical(c))
+    }
+}
+
+impl RolloutPolicy for HeuristicRollout {
+    fn choose_action(&self, state: &CompactState, legal_actions: &[Action], mut rng: &mut dyn Rng) -> Action {
+        if self.epsilon > 0.0 && rng.next_f64() < self.epsilon {
+            return UniformRollout.choose_action(state, legal_actions, rng);
+        }
+
+        let hand = state.hand(state.current_player());
+        for (i, &c_id) in hand.iter().enumerate() {
+            if state.known_playable(c_id) {
+                return Action::Play(i);
+            }
+        }
+
+        let useful_clues: Vec<Action> = legal_actions.iter().cloned().filter(|&action| {
+            match action {
+                Action::Clue(target, clue) => state.hand(target).iter().any(|&c_id| {
+                    let c = state.card(c_id).unwrap();
+                    clue.matches(c) && state.is_playable(c) && !state.known_playable(c_id)
+                }),
+                _ => false,
+            }
+        }).collect();
+        if !useful_clues.is_empty() {
+            return useful_clues[Range::new(0, useful_clues.len()).ind_sample(&mut rng)];
+        }
+
+        // Legal actions list a hand's discards oldest first.
+        let safe_discard = legal_actions.iter().cloned().find(|&action| {
+            match action {
+                Action::Discard(i) => HeuristicRollout::safe_to_discard(state, i),
+                _ => false,
+            }
+        });
+        if let Some(action) = safe_discard {
+            return action;
+        }
+
+        // Prefer clues that give new information over discarding something
+        // critical.
+        let clues: Vec<Action> = legal_actions.iter().cloned().filter(|&action| {
+            match action {
+                Action::Clue(target, clue) => state.hand(target).iter().any(|&c_id| {
+                    clue_is_new(clue, state.knowledge(c_id))
+                }),
+                _ => false,
+            }
+        }).collect();
+        if !clues.is_empty() {
+            return clues[Range::new(0, clues.len()).ind_sample(&mut rng)];
+        }
+
+        UniformRollout.choose_action(state, legal_actions, rng)
+    }
+}
+
+// Whether a clue could still narrow down a card with this knowledge.
+pub(crate) fn clue_is_new(clue: Clue, knowledge: u16) -> bool {
+    (knowledge & clue_dimension(clue)).count_ones() > 1
+}
@@ -0,0 +1,367 @@
+use std::collections::HashMap;
+
+use compact::{copies, decode_card, CompactResult, CompactState, NO_CARD, NUM_CARDS};
+use hanabi::{Action, GameState, HAND_SIZE, PLAYERS};
+
+// An exact solver for the perfect information game, where every card
+// including the deck order is known to everyone. Its value is an upper bound
+// on what any strategy could score with the same deck.
+//
+// The search is a depth-first search with memoization. With everything
+// visible, clues carry no information, so giving a clue only passes the turn
+// and spends a clue token; a single clue stands in for all of them. Playing
+// a card that is not playable is never better than discarding it, so
+// misplays are only tried when discarding is not allowed. A position is
+// abandoned as soon as one line reaches its upper bound.
+
+#[derive(Debug, Clone)]
+pub struct Solution {
+    pub score: i8,
+    // False if the node limit cut the search short, in which case score is
+    // only the best found so far.
+    pub exact: bool,
+    pub nodes: usize,
+    // A line of play achieving the score.
+    pub line: Vec<Action>,
+}
+
+// The parts of a position that matter when every card is known. Slots in a
+// hand are interchangeable, so hands are sorted, and the discard pile only
+// matters through which cards remain.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+struct PositionKey {
+    hands: [[u8; HAND_SIZE]; 4],
+    piles: [u8; 5],
+    next_card: u8,
+    current: u8,
+    final_turn: u8,
+    clues: u8,
+    strikes: u8,
+}
+
+impl PositionKey {
+    fn new(state: &CompactState) -> PositionKey {
+        let mut hands = [[NO_CARD; HAND_SIZE]; 4];
+        for (p, hand) in hands.iter_mut().enumerate() {
+            for (slot, &c_id) in state.hand(PLAYERS[p]).iter().enumerate() {
+                hand[slot] = state.cards[c_id as usize];
+            }
+            hand.sort();
+        }
+        PositionKey {
+            hands,
+            piles: state.piles,
+            next_card: state.next_card,
+            current: state.current,
+            final_turn: state.final_turn,
+            clues: state.clues,
+            strikes: state.strikes,
+        }
+    }
+}
+
+// A memoized action. Plays and discards name the card rather than the slot,
+// since positions that only differ in the order of a hand share an entry.
+#[derive(Debug, Clone, Copy)]
+enum Move {
+    Play(u8),
+    Discard(u8),
+    Clue(Action),
+}
+
+impl Move {
+    fn new(state: &CompactState, action: Action) -> Move {
+        let card = |i: usize| state.cards[state.hand(state.current_player())[i] as usize];
+        match action {
+            Action::Play(i) => Move::Play(card(i)),
+            Action::Discard(i) => Move::Discard(card(i)),
+            clue => Move::Clue(clue),
+        }
+    }
+
+    // The action making this move in a state with the same key.
+    fn action(self, state: &CompactState) -> Action {
+        let slot = |code: u8| {
+            state.hand(state.current_player()).iter()
+                .position(|&c_id| state.cards[c_id as usize] == code)
+                .unwrap()
+        };
+        match self {
+            Move::Play(code) => Action::Play(slot(code)),
+            Move::Discard(code) => Action::Discard(slot(code)),
+            Move::Clue(clue) => clue,
+        }
+    }
+}
+
+pub struct OmniscientSolver {
+    // The best score found from each position searched so far, the move
+    // that achieves it, and whether the search there was complete. Only
+    // complete entries are reused; the others just record the line.
+    memo: HashMap<PositionKey, (i8, Move, bool)>,
+    max_nodes: Option<usize>,
+    nodes: usize,
+    aborted: bool,
+}
+
+impl Default for OmniscientSolver {
+    fn default() -> OmniscientSolver {
+        OmniscientSolver::new()
+    }
+}
+
+impl OmniscientSolver {
+    pub fn new() -> OmniscientSolver {
+        OmniscientSolver {
+            memo: HashMap::new(),
+            max_nodes: None,
+            nodes: 0,
+            aborted: false,
+        }
+    }
+
+    // Stop searching after visiting this many positions.
+    pub fn set_max_nodes(&mut self, max_nodes: usize) {
+        self.max_nodes = Some(max_nodes);
+    }
+
+    // Solves a fully determined state, such as GameState::initial(&deck).
+    pub fn solve(&mut self, state: &GameState) -> Solution {
+        self.memo.clear();
+        self.nodes = 0;
+        self.aborted = false;
+
+        let root = CompactState::from(state);
+        let score = self.search(&root);
+        Solution {
+            score,
+            exact: !self.aborted,
+            nodes: self.nodes,
+            line: self.line(root),
+        }
+    }
+
+    // Returns the best score from a position.
+    fn search(&mut self, state: &CompactState) -> i8 {
+        self.nodes += 1;
+        if self.max_nodes.is_some_and(|max| self.nodes >= max) {
+            self.aborted = true;
+        }
+
+        let key = PositionKey::new(state);
+        if let Some(&(score, _, true)) = self.memo.get(&key) {
+            return score;
+        }
+
+        let bound = compact_score_bound(state).score;
+        let mut best: (i8, Option<Action>) = (-1, None);
+        for action in candidate_actions(state) {
+            let mut child = *state;
+            let score = match child.act(action) {
+                CompactResult::Acted => self.search(&child),
+                CompactResult::Finished(score) => score,
+                CompactResult::Illegal(_) | CompactResult::Error(_) => {
+                    panic!("Solver needs every card to be known and legal actions");
+                },
+            };
+            if score > best.0 {
+                best = (score, Some(action));
+            }
+            if best.0 >= bound || self.aborted {
+                break;
+            }
+        }
+
+        if let Some(action) = best.1 {
+            self.memo.insert(key, (best.0, Move::new(state, action), !self.aborted));
+        }
+        best.0
+    }
+
+    // Follows the memoized best actions from the root.
+    fn line(&self, mut state: CompactState) -> Vec<Action> {
+        let mut line = Vec::new();
+        while let Some(&(_, next, _)) = self.memo.get(&PositionKey::new(&state)) {
+            let action = next.action(&state);
+            line.push(action);
+            if state.act(action) != CompactResult::Acted {
+                break;
+            }
+        }
+        line
+    }
+}
+
+// Which limit on the score is the tightest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Constraint {
+    // Nothing rules out a perfect score.
+    None,
+    // Every copy of some card needed for the piles is gone.
+    LostCards,
+    // Fewer plays are left than cards still to be played.
+    Pace,
+    // Too many needed cards sit near the bottom of the deck to all be played
+    // after they are drawn. depth counts the cards from the bottom.
+    BuriedCards { depth: usize },
+}
+
+// A fast upper bound on the score, with the bound each constraint gives on
+// its own. The bound is never below what the omniscient solver finds, and is
+// far cheaper to compute. In a player's view it is looser, since cards the
+// player cannot see may be anything still unaccounted for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScoreBound {
+    pub score: i8,
+    pub constraint: Constraint,
+    pub lost_cards: i8,
+    pub pace: i8,
+    pub buried_cards: i8,
+}
+
+pub fn score_bound(state: &GameState) -> ScoreBound {
+    compact_score_bound(&CompactState::from(state))
+}
+
+// The score cannot exceed what the lost cards allow, nor the current score
+// plus the plays left before the game ends.
+//
+// Cards in a suit are played in order, so once the earliest remaining copy of
+// some rank is still in the deck, that rank and every higher one must wait
+// until it is drawn. Every play or discard draws while the deck lasts, and
+// the last draw is followed by one final turn for each player, so at most
+// (cards below it) + 4 cards are played after any given card is drawn.
+pub(crate) fn compact_score_bound(state: &CompactState) -> ScoreBound {
+    let score = state.score();
+    let lost_cards = state.max_score();
+    let reachable = (lost_cards - score) as usize;
+    let pace = score + reachable.min(state.plays_left()) as i8;
+
+    // The id of the earliest copy of each card that is still in a hand or
+    // the deck. Cards in hand have ids below next_card. In a player's view
+    // some cards are unknown, and any copy not accounted for may be the
+    // earliest unknown card.
+    let total_cards = state.next_card as usize + state.deck_size as usize;
+    let mut earliest = [NUM_CARDS; 25];
+    let mut located = [0u8; 25];
+    let mut first_unknown = NUM_CARDS;
+    for c_id in (0..total_cards).rev() {
+        if (state.played | state.discarded) & (1 << c_id) == 0 {
+            match state.cards[c_id] {
+                NO_CARD => first_unknown = c_id,
+                code => {
+                    earliest[code as usize] = c_id;
+                    located[code as usize] += 1;
+                },
+            }
+        }
+    }
+    for code in 0..25u8 {
+        let played = if code % 5 < state.piles[(code / 5) as usize] { 1 } else { 0 };
+        if located[code as usize] + state.discard_counts[code as usize] + played < copies(code) {
+            earliest[code as usize] = earliest[code as usize].min(first_unknown);
+        }
+    }
+    // Each suit's pile can only grow up to its first lost rank.
+    let limits: Vec<usize> = (0..5u8).map(|suit| {
+        (0..5u8).find(|&r| {
+            let code = suit * 5 + r;
+            state.discard_counts[code as usize] == copies(code)
+        }).unwrap_or(5) as usize
+    }).collect();
+
+    let mut excess = 0;
+    let mut depth = 0;
+    for c_id in state.next_card as usize..total_cards {
+        let needed: usize = (0..5).map(|suit| {
+            let height = state.piles[suit] as usize;
+            (height..limits[suit])
+                .find(|&r| earliest[suit * 5 + r] >= c_id)
+                .map_or(0, |r| limits[suit] - r)
+        }).sum();
+        let capacity = total_cards - 1 - c_id + PLAYERS.len();
+        if needed > capacity + excess {
+            excess = needed - capacity;
+            depth = total_cards - c_id;
+        }
+    }
+    let buried_cards = lost_cards - excess as i8;
+
+    let bound = lost_cards.min(pace).min(buried_cards);
+    let constraint = if bound == 25 {
+        Constraint::None
+    } else if bound == lost_cards {
+        Constraint::LostCards
+    } else if bound == pace {
+        Constraint::Pace
+    } else {
+        Constraint::BuriedCards { depth }
+    };
+    ScoreBound {
+        score: bound,
+        constraint,
+        lost_cards,
+        pace,
+        buried_cards,
+    }
+}
+
+// The actions worth searching, most promising first: playable cards, one
+// clue, discards from the least to the most valuable, then misplays.
+// Duplicate cards in a hand are only tried once.
+fn candidate_actions(state: &CompactState) -> Vec<Action> {
+    let hand = state.hand(state.current_player());
+    let card = |i: usize| decode_card(state.cards[hand[i] as usize]);
+    let mut seen_plays = Vec::new();
+    let mut seen_discards = Vec::new();
+
+    let mut plays = Vec::new();
+    let mut discards = Vec::new();
+    let mut misplays = Vec::new();
+    for i in 0..hand.len() {
+        let c = card(i);
+        if state.is_playable(c) {
+            if !seen_plays.contains(&c) {
+                seen_plays.push(c);
+                plays.push(Action::Play(i));
+            }
+        } else if state.clues == 8 && state.strikes < 2 && !seen_plays.contains(&c) {
+            // A misplay still draws a card.
+            seen_plays.push(c);
+            misplays.push(Action::Play(i));
+        }
+        if state.clues < 8 && !seen_discards.contains(&c) {
+            seen_discards.push(c);
+            discards.push(Action::Discard(i));
+        }
+    }
+    discards.sort_by_key(|&action| {
+        match action {
+            Action::Discard(i) => {
+                let c = card(i);
+                if state.is_dead(c) {
+                    0
+                } else if !state.is_critical(c) {
+                    1
+                } else {
+                    2
+                }
+            },
+            _ => unreachable!(),
+        }
+    });
+
+    let mut actions = plays;
+    if state.clues > 0 {
+        if let Some(&clue) = state.legal_actions().iter().find(|a| matches!(a, Action::Clue(..))) {
+            actions.push(clue);
+        }
+    }
+    actions.extend(discards);
+    actions.extend(misplays);
+    if actions.is_empty() && !hand.is_empty() {
+        // Only a game-ending misplay is left.
+        actions.push(Action::Play(0));
+    }
+    actions
+}
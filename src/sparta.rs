@@ -0,0 +1,185 @@
+use std::sync::Arc;
+
+use rand::Rng;
+
+use agent::Agent;
+use compact::CompactState;
+use hanabi::{Action, ActionResult, Card, GameState, Player};
+
+// Single-agent search on top of a blueprint strategy (SPARTA). The searching
+// player assumes every player, itself included after the current move,
+// follows the blueprint. Each of its legal actions is scored by playing the
+// rest of the game with the blueprint in sampled deals of the hidden cards,
+// and the blueprint's own choice is only overridden when another action is
+// clearly better.
+//
+// Beliefs come from replaying the game in each sampled deal: a deal in which
+// the blueprint would have chosen differently than a partner actually did is
+// less likely. Rather than rejecting such deals outright, which would leave
+// nothing to search once a partner deviates from the blueprint, each
+// mismatch multiplies the deal's weight by a penalty.
+
+#[derive(Debug)]
+pub struct SpartaAgent {
+    blueprint: Arc<dyn Agent>,
+    samples: usize,
+    mismatch_penalty: f64,
+    min_effective_samples: f64,
+    threshold: f64,
+    confidence: f64,
+}
+
+impl SpartaAgent {
+    pub fn new(blueprint: Arc<dyn Agent>) -> SpartaAgent {
+        SpartaAgent {
+            blueprint,
+            samples: 100,
+            mismatch_penalty: 0.05,
+            min_effective_samples: 10.0,
+            threshold: 0.05,
+            confidence: 1.96,
+        }
+    }
+
+    // The number of deals sampled for each decision. Every legal action is
+    // played out once in each.
+    pub fn set_samples(&mut self, samples: usize) {
+        self.samples = samples;
+    }
+
+    // The factor by which a deal's weight shrinks for each partner action
+    // the blueprint would not have taken in it.
+    pub fn set_mismatch_penalty(&mut self, penalty: f64) {
+        self.mismatch_penalty = penalty;
+    }
+
+    // If the weights leave fewer effective samples than this, the beliefs
+    // are too uncertain to search and the blueprint's action is played.
+    pub fn set_min_effective_samples(&mut self, samples: f64) {
+        self.min_effective_samples = samples;
+    }
+
+    // Deviate from the blueprint only if the best action's mean score beats
+    // the blueprint action's by more than threshold points, and by more
+    // than confidence standard errors of the difference.
+    pub fn set_threshold(&mut self, threshold: f64, confidence: f64) {
+        self.threshold = threshold;
+        self.confidence = confidence;
+    }
+
+    // Whether the weighted mean of per-deal score differences clears both
+    // the threshold and the confidence test.
+    pub(crate) fn significant_gain(&self, differences: &[f64], weights: &[f64]) -> bool {
+        let total_weight: f64 = weights.iter().sum();
+        let squared_weights: f64 = weights.iter().map(|w| w * w).sum();
+        let effective_samples = total_weight * total_weight / squared_weights;
+        let mean = |values: &[f64]| -> f64 {
+            values.iter().zip(weights.iter()).map(|(v, w)| v * w).sum::<f64>() / total_weight
+        };
+        let gain = mean(differences);
+        let variance = mean(&differences.iter().map(|d| (d - gain) * (d - gain)).collect::<Vec<f64>>());
+        let standard_error = (variance / effective_samples).sqrt();
+        gain > self.threshold && gain > self.confidence * standard_error
+    }
+
+    // Samples a deal consistent with the view and replays the game in it,
+    // returning the replayed state with its full history and the deal's
+    // weight.
+    //
+    // GameState::determinize would give the current state of a deal
+    // directly, but weighing the deal means asking the blueprint what each
+    // partner would have done in their view at the time, and those past
+    // views only exist in a replay from the start. The deal is drawn with
+    // CompactState::determinize, which depends only on the rng, so seeded
+    // games stay reproducible.
+    fn sample_world(&self, view: &GameState, mut rng: &mut dyn Rng) -> (GameState, f64) {
+        let observer = view.current_player();
+        let mut deal = CompactState::from(view);
+        deal.determinize(&mut rng);
+
+        let total_cards = deal.next_card as usize + deal.deck_size as usize;
+        let deck: Vec<Card> = (0..total_cards).map(|c_id| deal.card(c_id as u8).unwrap()).collect();
+        let mut replay = GameState::initial(&deck);
+        let mut weight = 1.0;
+        for completed_action in view.action_log() {
+            let action = completed_action.action();
+            let actor: Player = replay.current_player();
+            if actor != observer && self.blueprint.choose_action(&replay.current_view(), rng) != action {
+                weight *= self.mismatch_penalty;
+            }
+            match replay.act(action) {
+                ActionResult::Acted(_) => {},
+                _ => panic!("Replaying the game in a consistent deal must succeed"),
+            }
+        }
+        (replay, weight)
+    }
+
+    // The final score when the actor takes an action and everyone follows
+    // the blueprint from then on.
+    fn playout(&self, mut world: GameState, action: Action, rng: &mut dyn Rng) -> f64 {
+        let mut action = action;
+        loop {
+            match world.act(action) {
+                ActionResult::Acted(_) => {},
+                ActionResult::Finished(score) => return score as f64,
+                ActionResult::Illegal(_) | ActionResult::Error(_) => {
+                    panic!("Blueprint chose an illegal action");
+                },
+            }
+            action = self.blueprint.choose_action(&world.current_view(), rng);
+        }
+    }
+}
+
+impl Agent for SpartaAgent {
+    fn choose_action(&self, view: &GameState, rng: &mut dyn Rng) -> Action {
+        let blueprint_action = self.blueprint.choose_action(view, rng);
+
+        let worlds: Vec<(GameState, f64)> = (0..self.samples).map(|_| self.sample_world(view, rng)).collect();
+        let total_weight: f64 = worlds.iter().map(|&(_, w)| w).sum();
+        let squared_weights: f64 = worlds.iter().map(|&(_, w)| w * w).sum();
+        let effective_samples = total_weight * total_weight / squared_weights;
+        if effective_samples < self.min_effective_samples {
+            return blueprint_action;
+        }
+
+        // Sorted, since the order of clue targets in legal_actions varies
+        // from run to run and decides ties.
+        let mut candidates = view.legal_actions();
+        if !candidates.contains(&blueprint_action) {
+            candidates.push(blueprint_action);
+        }
+        candidates.sort();
+        let scores: Vec<Vec<f64>> = candidates.iter()
+            .map(|&action| worlds.iter().map(|(world, _)| self.playout(world.clone(), action, rng)).collect())
+            .collect();
+        let mean = |values: &[f64]| -> f64 {
+            values.iter().zip(worlds.iter()).map(|(v, &(_, w))| v * w).sum::<f64>() / total_weight
+        };
+
+        let means: Vec<f64> = scores.iter().map(|s| mean(s)).collect();
+        let blueprint_index = candidates.iter().position(|&a| a == blueprint_action).unwrap();
+        let mut best = blueprint_index;
+        for (i, &m) in means.iter().enumerate() {
+            if m > means[best] {
+                best = i;
+            }
+        }
+        if best == blueprint_index {
+            return blueprint_action;
+        }
+
+        // Both actions were played out in the same deals, so compare them
+        // deal by deal.
+        let differences: Vec<f64> = scores[best].iter().zip(scores[blueprint_index].iter())
+            .map(|(b, p)| b - p)
+            .collect();
+        let weights: Vec<f64> = worlds.iter().map(|&(_, w)| w).collect();
+        if self.significant_gain(&differences, &weights) {
+            candidates[best]
+        } else {
+            blueprint_action
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use hanabi::{Action, Card, CardId, Clue, CompletedAction, Fingerprint, GameState, Player, Suit};
+use hanabi::{HAND_SIZE, PLAYERS, SUITS};
+
+// Canonical forms of what a player sees, under the symmetries of the game.
+// Without variants, relabeling the suits consistently everywhere turns one
+// position into another that plays out identically. A canonical form picks
+// one representative of each class of symmetric positions, so statistics
+// gathered for one can be shared with all of them. ISMCTS uses it to key its
+// information sets; basic MCTS keys its nodes by the path from its root,
+// where every node is in the root's frame, so it has nothing to share.
+//
+// Card ids are only labels, so the canonical form also renumbers them: first
+// the cards in hand, by seat relative to the player to move and then by
+// slot, then the played and discarded cards in the order they left a hand,
+// then the rest in id order. Suits are then ordered by where they first
+// appear in the position, which takes a single pass instead of trying every
+// permutation.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Symmetry {
+    // Suit s is relabeled as SUITS[suits[s as usize]].
+    suits: [u8; 5],
+}
+
+impl Symmetry {
+    pub fn identity() -> Symmetry {
+        Symmetry {
+            suits: [0, 1, 2, 3, 4],
+        }
+    }
+
+    pub fn inverse(&self) -> Symmetry {
+        let mut suits = [0; 5];
+        for (s, &t) in self.suits.iter().enumerate() {
+            suits[t as usize] = s as u8;
+        }
+        Symmetry {
+            suits,
+        }
+    }
+
+    pub fn suit(&self, suit: Suit) -> Suit {
+        SUITS[self.suits[suit as usize] as usize]
+    }
+
+    pub fn card(&self, card: Card) -> Card {
+        Card {
+            suit: self.suit(card.suit),
+            rank: card.rank,
+        }
+    }
+
+    pub fn clue(&self, clue: Clue) -> Clue {
+        match clue {
+            Clue::Suit(s) => Clue::Suit(self.suit(s)),
+            Clue::Rank(r) => Clue::Rank(r),
+        }
+    }
+
+    // Plays and discards refer to hand slots, which no symmetry moves.
+    pub fn action(&self, action: Action) -> Action {
+        match action {
+            Action::Clue(target, clue) => Action::Clue(target, self.clue(clue)),
+            other => other,
+        }
+    }
+}
+
+pub struct CanonicalForm {
+    pub fingerprint: Fingerprint,
+    // Maps the original position's cards and actions into the canonical
+    // position. Its inverse maps actions back.
+    pub symmetry: Symmetry,
+}
+
+impl CanonicalForm {
+    pub fn hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.fingerprint.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+// The canonical form of what observer sees of a state.
+pub fn canonical_form(state: &GameState, observer: Player) -> CanonicalForm {
+    let labels = card_labels(state);
+    let relabel = |c_id: &CardId| CardId(labels[c_id]);
+    let mut visible: Vec<(CardId, Card)> = state.visible_cards(observer)
+        .filter_map(|c_id| state.card_map.get(&c_id).map(|&c| (relabel(&c_id), c)))
+        .collect();
+    visible.sort();
+
+    let symmetry = suit_order(&visible, &state.action_log);
+    let known_cards = visible.into_iter()
+        .map(|(c_id, c)| (c_id, symmetry.card(c)))
+        .collect();
+    let actions = state.action_log.iter()
+        .map(|action| transform_action(action, &symmetry, &labels))
+        .collect();
+
+    CanonicalForm {
+        fingerprint: Fingerprint {
+            known_cards,
+            actions,
+        },
+        symmetry,
+    }
+}
+
+fn transform_action(action: &CompletedAction, symmetry: &Symmetry, labels: &HashMap<CardId, usize>) -> CompletedAction {
+    match *action {
+        CompletedAction::Discarded(i, c) => CompletedAction::Discarded(i, symmetry.card(c)),
+        CompletedAction::Played(i, c) => CompletedAction::Played(i, symmetry.card(c)),
+        CompletedAction::Clued(target, clue, ref matching) => {
+            let mut matching: Vec<CardId> = matching.iter().map(|c_id| CardId(labels[c_id])).collect();
+            matching.sort();
+            CompletedAction::Clued(target, symmetry.clue(clue), matching)
+        },
+    }
+}
+
+// Orders the suits by where they first appear: among the visible cards by
+// label, or else in the action log. Where a suit first appears does not
+// depend on how suits are labeled, and no two suits first appear in the same
+// place, so symmetric positions end up with the same order. Suits that never
+// appear tie, but they are interchangeable anyway.
+fn suit_order(visible: &[(CardId, Card)], action_log: &[CompletedAction]) -> Symmetry {
+    let mut first = [usize::MAX; 5];
+    let mut appear = |suit: Suit, place: usize| {
+        let first = &mut first[suit as usize];
+        *first = (*first).min(place);
+    };
+    for (place, &(_, c)) in visible.iter().enumerate() {
+        appear(c.suit, place);
+    }
+    for (turn, action) in action_log.iter().enumerate() {
+        let place = visible.len() + turn;
+        match *action {
+            CompletedAction::Discarded(_, c) | CompletedAction::Played(_, c) => appear(c.suit, place),
+            CompletedAction::Clued(_, Clue::Suit(s), _) => appear(s, place),
+            CompletedAction::Clued(..) => {},
+        }
+    }
+
+    let mut order: Vec<usize> = (0..SUITS.len()).collect();
+    order.sort_by_key(|&s| first[s]);
+    let mut suits = [0; 5];
+    for (label, &s) in order.iter().enumerate() {
+        suits[s] = label as u8;
+    }
+    Symmetry {
+        suits,
+    }
+}
+
+// Replays the draws implied by the action log to find when each card left a
+// hand, and numbers the cards as described at the top of this file.
+fn card_labels(state: &GameState) -> HashMap<CardId, usize> {
+    let CardId(next_card) = state.next_card_id;
+    let total_cards = next_card + state.deck_size;
+
+    let mut hands: Vec<Vec<usize>> = (0..PLAYERS.len())
+        .map(|p| (0..HAND_SIZE).map(|slot| slot * PLAYERS.len() + p).collect())
+        .collect();
+    let mut drawn = PLAYERS.len() * HAND_SIZE;
+    let mut departed = Vec::new();
+    for (turn, action) in state.action_log.iter().enumerate() {
+        let hand = &mut hands[turn % PLAYERS.len()];
+        match *action {
+            CompletedAction::Discarded(i, _) | CompletedAction::Played(i, _) => {
+                departed.push(hand.remove(i));
+                if drawn < total_cards {
+                    hand.push(drawn);
+                    drawn += 1;
+                }
+            },
+            CompletedAction::Clued(..) => {},
+        }
+    }
+
+    let mut labels = HashMap::new();
+    let current = state.current_turn.index();
+    for offset in 0..PLAYERS.len() {
+        let player = PLAYERS[(current + offset) % PLAYERS.len()];
+        for &c_id in state.hands[&player].iter() {
+            let label = labels.len();
+            labels.insert(c_id, label);
+        }
+    }
+    for id in departed {
+        let label = labels.len();
+        labels.entry(CardId(id)).or_insert(label);
+    }
+    for id in 0..total_cards {
+        let label = labels.len();
+        labels.entry(CardId(id)).or_insert(label);
+    }
+    labels
+}
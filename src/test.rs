@@ -1,8 +1,26 @@
 use std::collections::HashMap;
-use rand;
+use std::sync::{Arc, Mutex};
 
-use hanabi::{Card, GameState, Suit, Rank, Player, Clue, Action, ActionResult};
-use basic_mcts::MctsState;
+use rand::{Rng, SeedableRng, StdRng};
+use rayon;
+use rand::distributions::{IndependentSample, Range};
+
+use hanabi::{Card, CardId, GameState, Suit, Rank, Player, Clue, Action, ActionResult};
+use agent::{Agent, HeuristicAgent};
+use basic_mcts::{
+    MctsConfig, MctsState, Node, Objective, SearchBudget, SearchMode, Selection, SelectionRule, StopReason, Widening,
+};
+use evaluation::Evaluator;
+use conventions::ConventionAgent;
+use expectimax::ExpectimaxAgent;
+use hat::{clue_value, Board, CommonKnowledge, HatAgent, Question};
+use compact::{encode_card, CompactResult, CompactState};
+use ismcts::IsmctsState;
+use particle_filter::{ParticleFilter, UniformLikelihood};
+use rollout::{clue_is_new, rollout, HeuristicRollout, RolloutPolicy, UniformRollout};
+use solver::{score_bound, OmniscientSolver};
+use sparta::SpartaAgent;
+use symmetry::canonical_form;
 
 const SAMPLE_DECK : [Card; 50] = [
     Card { suit: Suit::Yellow, rank: Rank::One },
@@ -112,3 +130,912 @@ fn test_mcts() {
     alice_mcts.run_playout(&mut rng);
 }
 */
+
+fn seeded_rng(seed: usize) -> StdRng {
+    SeedableRng::from_seed(&[seed][..])
+}
+
+#[test]
+fn particles_stay_consistent_with_clues() {
+    let mut rng = seeded_rng(1);
+    let mut state = GameState::initial(&SAMPLE_DECK);
+    let mut filter = ParticleFilter::new(state.player_view(Player::Bob), Player::Bob, 50, &mut rng);
+
+    let completed_action = match state.act(Action::Clue(Player::Bob, Clue::Rank(Rank::One))) {
+        ActionResult::Acted(completed_action) => completed_action,
+        _ => panic!("Acting failed!"),
+    };
+    let view = state.player_view(Player::Bob);
+    filter.observe_with(&completed_action, view.clone(), &UniformLikelihood, &mut rng);
+
+    assert!(filter.effective_sample_size() > 0.0);
+    for particle in filter.particles().iter().filter(|p| p.weight > 0.0) {
+        for &c_id in view.hand(Player::Bob).iter() {
+            let card = particle.assignment[&c_id];
+            assert!(view.card_consistent(c_id, card));
+        }
+    }
+}
+
+#[test]
+fn playouts_start_from_particles() {
+    let mut rng = seeded_rng(2);
+    let state = GameState::initial(&SAMPLE_DECK);
+    let alice_view = state.player_view(Player::Alice);
+    let filter = ParticleFilter::new(alice_view.clone(), Player::Alice, 20, &mut rng);
+    let mcts = MctsState::new(alice_view, 1.4);
+
+    for _ in 0..20 {
+        let world = filter.sample(&mut rng);
+        let (updates, result) = mcts.run_playout_from(world, &mut rng);
+        assert!((0.0..=25.0).contains(&result));
+        mcts.update(updates, result);
+    }
+    let visits: usize = mcts.root_report().iter().map(|stats| stats.visits).sum();
+    assert_eq!(visits, 20);
+}
+
+#[test]
+fn advancing_keeps_a_smaller_tree() {
+    let mut rng = seeded_rng(3);
+    let mut state = GameState::initial(&SAMPLE_DECK);
+    let mut mcts = MctsState::new(state.current_view(), 1.4);
+    for _ in 0..200 {
+        let (updates, result) = mcts.run_playout(&mut rng);
+        mcts.update(updates, result);
+    }
+    let nodes = mcts.num_nodes();
+
+    let action = mcts.choose_action(&mut rng);
+    let completed_action = match state.act(action) {
+        ActionResult::Acted(completed_action) => completed_action,
+        _ => panic!("Acting failed!"),
+    };
+    mcts.advance(&completed_action, state.current_view());
+    assert!(mcts.num_nodes() < nodes);
+    for _ in 0..200 {
+        let (updates, result) = mcts.run_playout(&mut rng);
+        mcts.update(updates, result);
+    }
+}
+
+#[test]
+fn choosing_without_playouts_picks_a_legal_action() {
+    let mut rng = seeded_rng(4);
+    let state = GameState::initial(&SAMPLE_DECK);
+    let legal_actions = state.legal_actions();
+
+    let mcts = MctsState::new(state.current_view(), 1.4);
+    assert!(legal_actions.contains(&mcts.choose_action(&mut rng)));
+    let ismcts = IsmctsState::new(state.current_view(), 1.4);
+    assert!(legal_actions.contains(&ismcts.choose_action(&mut rng)));
+}
+
+#[test]
+fn compact_state_tracks_game_state() {
+    let mut rng = seeded_rng(5);
+    let mut state = GameState::initial(&SAMPLE_DECK);
+    let mut compact = CompactState::from(&state);
+    assert_eq!(compact.legal_actions().len(), state.legal_actions().len());
+    loop {
+        let mut legal_actions = state.legal_actions();
+        legal_actions.sort();
+        let action = *rng.choose(&legal_actions).unwrap();
+        match (state.act(action), compact.act(action)) {
+            (ActionResult::Acted(_), CompactResult::Acted) => {
+                assert_eq!(compact, CompactState::from(&state));
+            },
+            (ActionResult::Finished(score), CompactResult::Finished(compact_score)) => {
+                assert_eq!(score, compact_score);
+                break;
+            },
+            (result, compact_result) => panic!("{:?} but {:?}", result, compact_result),
+        }
+    }
+}
+
+#[test]
+fn a_single_sample_leaves_a_wide_interval() {
+    let mut rng = seeded_rng(6);
+    let state = GameState::initial(&SAMPLE_DECK);
+    let mcts = MctsState::new(state.current_view(), 1.4);
+    let (updates, result) = mcts.run_playout(&mut rng);
+    mcts.update(updates, result);
+
+    let report = mcts.root_report();
+    assert_eq!(report[0].visits, 1);
+    assert!(report[0].upper - report[0].lower > 10.0);
+}
+
+#[test]
+fn risk_averse_rewards_span_the_score_range() {
+    for &a in [0.0, 0.5, 2.0].iter() {
+        let objective = Objective::RiskAverse(a);
+        assert_eq!(objective.reward(0.0, false), 0.0);
+        assert!((objective.reward(25.0, false) - 25.0).abs() < 1e-9);
+        assert!(objective.reward(10.0, false) >= 10.0 - 1e-9);
+    }
+}
+
+#[test]
+fn clue_classes_follow_what_the_target_learns() {
+    let state = GameState::initial(&SAMPLE_DECK);
+    let classes = state.action_classes();
+    let class_of = |action: Action| classes.iter().position(|class| class.contains(&action)).unwrap();
+
+    // Both touch only Cathy's red three, but say different things about it.
+    let red = Action::Clue(Player::Cathy, Clue::Suit(Suit::Red));
+    let three = Action::Clue(Player::Cathy, Clue::Rank(Rank::Three));
+    assert!(class_of(red) != class_of(three));
+
+    let members: usize = classes.iter().map(|class| class.len()).sum();
+    assert_eq!(members, state.legal_actions().len());
+}
+
+#[test]
+fn suit_relabelings_share_a_canonical_form() {
+    let relabel = |suit: Suit| match suit {
+        Suit::Red => Suit::Blue,
+        Suit::Blue => Suit::Purple,
+        Suit::Purple => Suit::Red,
+        other => other,
+    };
+    let deck: Vec<Card> = SAMPLE_DECK.iter().map(|c| Card { suit: relabel(c.suit), rank: c.rank }).collect();
+    let mut state = GameState::initial(&SAMPLE_DECK);
+    let mut relabeled = GameState::initial(&deck);
+    for &(action, relabeled_action) in [
+        (Action::Clue(Player::Cathy, Clue::Suit(Suit::Red)), Action::Clue(Player::Cathy, Clue::Suit(Suit::Blue))),
+        (Action::Play(0), Action::Play(0)),
+        (Action::Clue(Player::Alice, Clue::Rank(Rank::One)), Action::Clue(Player::Alice, Clue::Rank(Rank::One))),
+    ].iter() {
+        state.act(action);
+        relabeled.act(relabeled_action);
+        let player = state.current_player();
+        let form = canonical_form(&state, player);
+        let relabeled_form = canonical_form(&relabeled, player);
+        assert_eq!(form.hash(), relabeled_form.hash());
+
+        let clue = Action::Clue(Player::Alice, Clue::Suit(Suit::Purple));
+        let relabeled_clue = Action::Clue(Player::Alice, Clue::Suit(Suit::Red));
+        assert_eq!(form.symmetry.action(clue), relabeled_form.symmetry.action(relabeled_clue));
+    }
+}
+
+// The sample deck after the heuristic agent has played the given number of
+// turns with every card visible.
+fn heuristic_position(turns: usize) -> GameState {
+    let mut rng = seeded_rng(7);
+    let mut state = GameState::initial(&SAMPLE_DECK);
+    for _ in 0..turns {
+        let action = HeuristicAgent.choose_action(&state, &mut rng);
+        match state.act(action) {
+            ActionResult::Acted(_) => {},
+            result => panic!("The game ended early: {:?}", result),
+        }
+    }
+    state
+}
+
+#[test]
+fn solver_line_reaches_its_score() {
+    let mut state = heuristic_position(20);
+    let solution = OmniscientSolver::new().solve(&state);
+    assert!(solution.exact);
+
+    let mut score = None;
+    for &action in solution.line.iter() {
+        match state.act(action) {
+            ActionResult::Acted(_) => {},
+            ActionResult::Finished(final_score) => score = Some(final_score),
+            result => panic!("The line made an illegal move: {:?}", result),
+        }
+    }
+    assert_eq!(score, Some(solution.score));
+}
+
+#[test]
+fn score_bound_is_never_below_the_solver() {
+    for &turns in [20, 26, 32, 38].iter() {
+        let state = heuristic_position(turns);
+        let solution = OmniscientSolver::new().solve(&state);
+        assert!(solution.exact);
+        let bound = score_bound(&state);
+        assert!(bound.score >= solution.score,
+                "Bound {:?} is below the solved score {} after {} turns", bound, solution.score, turns);
+    }
+}
+
+#[test]
+fn score_bound_holds_in_player_views() {
+    for &turns in [20, 32].iter() {
+        let state = heuristic_position(turns);
+        let solution = OmniscientSolver::new().solve(&state);
+        let full = score_bound(&state);
+        for &player in [Player::Alice, Player::Bob, Player::Cathy, Player::Dave].iter() {
+            // Hiding cards can only loosen the bound.
+            let bound = score_bound(&state.player_view(player));
+            assert!(bound.score >= full.score && bound.score >= solution.score,
+                    "{:?}'s view bound {:?} is below {:?} after {} turns", player, bound, full, turns);
+        }
+    }
+}
+
+#[test]
+fn sparta_significance_needs_a_clear_gain() {
+    let agent = SpartaAgent::new(Arc::new(HeuristicAgent));
+    let weights = vec![1.0; 20];
+    assert!(agent.significant_gain(&[1.0; 20], &weights));
+    // Below the threshold, however consistent.
+    assert!(!agent.significant_gain(&[0.01; 20], &weights));
+    // Above the threshold on average, but too noisy.
+    let noisy: Vec<f64> = (0..20).map(|i| if i % 2 == 0 { 5.2 } else { -5.0 }).collect();
+    assert!(!agent.significant_gain(&noisy, &weights));
+    // Weights shift the mean: the only deal that gains barely counts.
+    let mut skewed = vec![0.0; 20];
+    skewed[0] = 20.0;
+    let mut skewed_weights = vec![1.0; 20];
+    skewed_weights[0] = 0.01;
+    assert!(!agent.significant_gain(&skewed, &skewed_weights));
+}
+
+#[test]
+fn sparta_plays_legal_actions_and_defers_to_the_blueprint() {
+    let state = heuristic_position(12);
+    let view = state.current_view();
+    let mut agent = SpartaAgent::new(Arc::new(HeuristicAgent));
+    agent.set_samples(10);
+    agent.set_min_effective_samples(1.0);
+    let action = agent.choose_action(&view, &mut seeded_rng(3));
+    assert!(view.legal_actions().contains(&action));
+
+    // With an unreachable threshold it never deviates.
+    agent.set_threshold(f64::INFINITY, 1.96);
+    let blueprint_action = HeuristicAgent.choose_action(&view, &mut seeded_rng(5));
+    assert_eq!(agent.choose_action(&view, &mut seeded_rng(5)), blueprint_action);
+}
+
+// Plays out actions from a state, panicking if the game ends.
+fn play_actions(mut state: GameState, actions: &[Action]) -> GameState {
+    for &action in actions {
+        match state.act(action) {
+            ActionResult::Acted(_) => {},
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+    state
+}
+
+fn convention_action(state: &GameState) -> Action {
+    ConventionAgent.choose_action(&state.current_view(), &mut seeded_rng(0))
+}
+
+#[test]
+fn conventions_play_clue_focus_and_chop_save() {
+    // Cathy holds G5 on her chop, then R3, B1 and B4.
+    let mut deck = SAMPLE_DECK.to_vec();
+    deck.swap(2, 6);
+    let state = GameState::initial(&deck);
+
+    // A clue touching only B1 asks for it to be played.
+    let clued = play_actions(state.clone(), &[Action::Clue(Player::Cathy, Clue::Rank(Rank::One))]);
+    let bob_action = convention_action(&clued);
+    let cathy_turn = play_actions(clued, &[bob_action]);
+    assert_eq!(convention_action(&cathy_turn), Action::Play(2));
+
+    // A clue touching the chop saves a 5 rather than asking for a play.
+    let saved = play_actions(state, &[Action::Clue(Player::Cathy, Clue::Rank(Rank::Five))]);
+    let bob_action = convention_action(&saved);
+    let cathy_turn = play_actions(saved, &[bob_action]);
+    assert_ne!(convention_action(&cathy_turn), Action::Play(0));
+}
+
+#[test]
+fn conventions_finesse() {
+    // Cathy holds R2 in her newest slot, and Bob's newest card is R1.
+    let mut deck = SAMPLE_DECK.to_vec();
+    deck.swap(14, 21);
+    let state = GameState::initial(&deck);
+
+    // R2 is one away from playable and no R1 is touched, so Bob blind-plays
+    // his newest card, and then Cathy plays the R2.
+    let clued = play_actions(state, &[Action::Clue(Player::Cathy, Clue::Rank(Rank::Two))]);
+    assert_eq!(convention_action(&clued), Action::Play(3));
+    let cathy_turn = play_actions(clued, &[Action::Play(3)]);
+    assert_eq!(convention_action(&cathy_turn), Action::Play(3));
+}
+
+#[test]
+fn conventions_good_touch() {
+    // Once Bob's R1 is touched, nobody touches the R1 in Alice's hand.
+    let clued = play_actions(GameState::initial(&SAMPLE_DECK), &[Action::Clue(Player::Bob, Clue::Suit(Suit::Red))]);
+    let bob_action = convention_action(&clued);
+    let cathy_turn = play_actions(clued, &[bob_action]);
+    let cathy_action = convention_action(&cathy_turn);
+    assert_ne!(cathy_action, Action::Clue(Player::Alice, Clue::Rank(Rank::One)));
+    assert_ne!(cathy_action, Action::Clue(Player::Alice, Clue::Suit(Suit::Red)));
+}
+
+#[test]
+fn hat_clue_values() {
+    assert_eq!(clue_value(Player::Alice, Action::Clue(Player::Bob, Clue::Rank(Rank::One))), Some(0));
+    assert_eq!(clue_value(Player::Alice, Action::Clue(Player::Bob, Clue::Suit(Suit::Red))), Some(1));
+    assert_eq!(clue_value(Player::Alice, Action::Clue(Player::Dave, Clue::Suit(Suit::Red))), Some(5));
+    assert_eq!(clue_value(Player::Dave, Action::Clue(Player::Alice, Clue::Rank(Rank::Five))), Some(0));
+    assert_eq!(clue_value(Player::Alice, Action::Play(0)), None);
+
+    // Every value can be given from the first position.
+    let state = GameState::initial(&SAMPLE_DECK);
+    let mut values: Vec<usize> = state.legal_actions().into_iter()
+        .filter_map(|action| clue_value(Player::Alice, action))
+        .collect();
+    values.sort();
+    values.dedup();
+    assert_eq!(values, vec![0, 1, 2, 3, 4, 5]);
+}
+
+#[test]
+fn hat_answers_decode_to_the_hand() {
+    let board = Board::new();
+    let playable = |card: Card| card.rank == Rank::One;
+    for hand in SAMPLE_DECK.windows(4) {
+        let mut possible = vec![(1u32 << 25) - 1; 4];
+        for _ in 0..3 {
+            let question = Question::new(&possible, &board);
+            let answer = question.answer(hand, &board);
+            assert!(answer < 6);
+            question.apply(answer, &mut possible, &board);
+            for (&card, &mask) in hand.iter().zip(possible.iter()) {
+                assert!(mask & (1 << encode_card(card)) != 0, "{:?} ruled out of {:?}", card, hand);
+            }
+        }
+        // The first playable card, if any, is known to be playable.
+        if let Some(i) = hand.iter().position(|&c| playable(c)) {
+            assert!((0..25u8).filter(|&code| possible[i] & (1 << code) != 0).all(|code| code % 5 == 0));
+        }
+    }
+}
+
+#[test]
+fn hat_knowledge_is_common_and_sound() {
+    let mut state = GameState::initial(&SAMPLE_DECK);
+    let mut rng = seeded_rng(0);
+    for _ in 0..40 {
+        let views: Vec<CommonKnowledge> = [Player::Alice, Player::Bob, Player::Cathy, Player::Dave].iter()
+            .map(|&p| CommonKnowledge::new(&state.player_view(p), p))
+            .collect();
+        for (c_id, &card) in SAMPLE_DECK.iter().enumerate() {
+            let possible = views[0].possible(c_id);
+            assert!(views.iter().all(|knowledge| knowledge.possible(c_id) == possible));
+            if state.hands.values().any(|hand| hand.contains(&CardId(c_id))) {
+                assert!(possible & (1 << encode_card(card)) != 0);
+            }
+        }
+        let action = HatAgent.choose_action(&state.current_view(), &mut rng);
+        match state.act(action) {
+            ActionResult::Acted(_) => {},
+            result => panic!("The game ended early: {:?}", result),
+        }
+    }
+}
+
+// Scores every position the same.
+#[derive(Debug)]
+struct ConstantEvaluator(f64);
+
+impl Evaluator for ConstantEvaluator {
+    fn evaluate(&self, _state: &CompactState) -> f64 {
+        self.0
+    }
+}
+
+#[test]
+fn rollout_depth_cuts_playouts_short() {
+    let mut rng = seeded_rng(8);
+    let state = GameState::initial(&SAMPLE_DECK);
+
+    let mut compact = CompactState::from(&state);
+    let mut trace = Vec::new();
+    assert_eq!(rollout(&mut compact, &UniformRollout, Some(3), Some(&mut trace), &mut rng), None);
+    assert_eq!(trace.len(), 3);
+    // The state is left where the rollout stopped.
+    let draws = trace.iter().filter(|&&(_, action)| !matches!(action, Action::Clue(..))).count();
+    assert_eq!(compact.deck_size() as usize, state.deck_size - draws);
+
+    // No game ends within a couple of moves of the start, so every playout
+    // is scored by the evaluator.
+    let mcts = MctsState::with_config(state.current_view(), MctsConfig {
+        rollout_depth: Some(1),
+        evaluator: Arc::new(ConstantEvaluator(17.0)),
+        ..MctsConfig::default()
+    });
+    for _ in 0..10 {
+        let (updates, result) = mcts.run_playout(&mut rng);
+        assert_eq!(result, 17.0);
+        mcts.update(updates, result);
+    }
+}
+
+#[test]
+fn view_hashes_follow_the_fingerprint() {
+    let mut rng = seeded_rng(9);
+    let mut state = GameState::initial(&SAMPLE_DECK);
+    let players = [Player::Alice, Player::Bob, Player::Cathy, Player::Dave];
+    for _ in 0..30 {
+        let view = state.current_view();
+        let mut worlds = Vec::new();
+        for _ in 0..2 {
+            let mut world = view.clone();
+            world.determinize(&HashMap::new(), &mut rng);
+            worlds.push(world);
+        }
+        for world in [&state].iter().cloned().chain(worlds.iter()) {
+            for &player in players.iter() {
+                assert_eq!(world.player_view_hash(player), world.player_view(player).fingerprint_hash());
+            }
+        }
+
+        // Worlds drawn from the same view look the same to the player to
+        // act, as does the view reduced in place.
+        let mut reduced = worlds[0].clone();
+        reduced.reduce_to_current_view();
+        let views = [worlds[0].current_view(), worlds[1].current_view(), reduced, view];
+        for other in views.iter() {
+            assert_eq!(other.fingerprint(), views[0].fingerprint());
+            assert_eq!(other.fingerprint_hash(), views[0].fingerprint_hash());
+        }
+
+        let legal_actions = state.legal_actions();
+        let action = legal_actions[Range::new(0, legal_actions.len()).ind_sample(&mut rng)];
+        match state.act(action) {
+            ActionResult::Acted(_) => {},
+            _ => break,
+        }
+    }
+}
+
+// Rewards a position only if a card has been played since the root.
+#[derive(Debug)]
+struct PlayedEvaluator(i8);
+
+impl Evaluator for PlayedEvaluator {
+    fn evaluate(&self, state: &CompactState) -> f64 {
+        if state.score() > self.0 { 25.0 } else { 0.0 }
+    }
+}
+
+#[test]
+fn search_stops_once_an_action_dominates() {
+    // Cathy knows her third card is playable, and with no clue tokens left
+    // every other move is a discard or a blind play.
+    let state = heuristic_position(26);
+    let view = state.current_view();
+    let compact = CompactState::from(&view);
+    assert!(compact.known_playable(compact.hand(Player::Cathy)[2]));
+    assert_eq!(view.clues, 0);
+
+    // Scoring each action by whether it played a card makes the known play
+    // worth the most in every world.
+    let mut mcts = MctsState::with_config(view, MctsConfig {
+        rollout_depth: Some(1),
+        evaluator: Arc::new(PlayedEvaluator(compact.score())),
+        ..MctsConfig::default()
+    });
+    let budget = SearchBudget {
+        max_playouts: Some(5000),
+        early_stop: Some(0.05),
+        check_interval: 50,
+        ..SearchBudget::default()
+    };
+    let report = mcts.search(&budget, None);
+    assert_eq!(report.stop_reason, StopReason::Decided);
+    assert!(report.playouts < 5000);
+    assert_eq!(mcts.choose_action(&mut seeded_rng(10)), Action::Play(2));
+}
+
+// Bob learns that his last card is the red one, which Alice then plays.
+// His second card is a one but not red, so it is playable.
+fn clued_position() -> GameState {
+    play_actions(GameState::initial(&SAMPLE_DECK), &[
+        Action::Clue(Player::Bob, Clue::Rank(Rank::One)),
+        Action::Clue(Player::Alice, Clue::Suit(Suit::Red)),
+        Action::Clue(Player::Bob, Clue::Suit(Suit::Red)),
+        Action::Clue(Player::Alice, Clue::Rank(Rank::One)),
+        Action::Play(1),
+    ])
+}
+
+#[test]
+fn dominated_actions_follow_the_clues() {
+    let state = clued_position();
+    let compact = CompactState::from(&state.current_view());
+    assert_eq!(compact.current_player(), Player::Bob);
+    assert!(compact.is_dominated(Action::Play(3)));
+    assert!(!compact.is_dominated(Action::Play(1)));
+    assert!(!compact.is_dominated(Action::Discard(0)));
+    assert!(!compact.is_dominated(Action::Clue(Player::Cathy, Clue::Rank(Rank::One))));
+}
+
+#[test]
+fn widening_admits_actions_as_visits_grow() {
+    let mut rng = seeded_rng(11);
+    let widening = Widening {
+        coefficient: 1.0,
+        exponent: 0.5,
+    };
+    assert_eq!(widening.width(0.0), 1);
+    assert_eq!(widening.width(3.0), 1);
+    assert_eq!(widening.width(4.0), 2);
+    assert_eq!(widening.width(99.0), 9);
+
+    let state = GameState::initial(&SAMPLE_DECK);
+    let legal_actions = state.legal_actions().len();
+    let mcts = MctsState::with_config(state.current_view(), MctsConfig {
+        widening: Some(widening),
+        ..MctsConfig::default()
+    });
+    for playouts in 1..=100 {
+        let (updates, result) = mcts.run_playout(&mut rng);
+        mcts.update(updates, result);
+        // UCB1 tries every admitted action before revisiting any, and the
+        // width grows by at most one per playout.
+        let visited = mcts.root_report().iter().filter(|stats| stats.visits > 0).count();
+        assert_eq!(visited, widening.width((playouts - 1) as f64).min(legal_actions));
+    }
+}
+
+// A node where Play(0) has 10 samples of 20 and Play(1) has 2 samples of
+// 15, so Play(0) has the better mean and Play(1) the larger exploration
+// bonus.
+fn selection_node() -> Node {
+    let mut node = Node::new();
+    for _ in 0..10 {
+        node.add_sample(Action::Play(0), 20.0);
+    }
+    for _ in 0..2 {
+        node.add_sample(Action::Play(1), 15.0);
+    }
+    node
+}
+
+fn select_with(node: &Node, legal_actions: &[Action], rule: SelectionRule, exploration: f64, reward_scale: f64, priors: &[f64]) -> Action {
+    let selection = Selection {
+        rule,
+        exploration,
+        reward_offset: 0.0,
+        reward_scale,
+        priors,
+        rave_equivalence: None,
+        loss_reward: 0.0,
+    };
+    node.select(legal_actions, &selection, &mut seeded_rng(12))
+}
+
+#[test]
+fn selection_rules_pick_the_expected_child() {
+    let node = selection_node();
+    let actions = [Action::Play(0), Action::Play(1)];
+
+    // UCB1 with rewards in [0, 1]: 0.8 + 1.4 * 0.50 < 0.6 + 1.4 * 1.11.
+    assert_eq!(select_with(&node, &actions, SelectionRule::Ucb1, 1.4, 25.0, &[]), Action::Play(1));
+    // Unnormalized, a gap of 5 points swamps the exploration term.
+    assert_eq!(select_with(&node, &actions, SelectionRule::Ucb1, 1.4, 1.0, &[]), Action::Play(0));
+
+    // With a smaller constant UCB1 still explores, 0.8 + 0.5 * 0.50 <
+    // 0.6 + 0.5 * 1.11, but UCB1-Tuned caps the variance term at 1/4 and
+    // halves the bonus: 0.8 + 0.5 * 0.25 > 0.6 + 0.5 * 0.56.
+    assert_eq!(select_with(&node, &actions, SelectionRule::Ucb1, 0.5, 25.0, &[]), Action::Play(1));
+    assert_eq!(select_with(&node, &actions, SelectionRule::Ucb1Tuned, 0.5, 25.0, &[]), Action::Play(0));
+
+    // PUCT follows the priors: 0.8 + 1.4 * 0.1 * sqrt(12) / 11 < 0.6 + 1.4 *
+    // 0.9 * sqrt(12) / 3, and the other way round with the priors swapped.
+    assert_eq!(select_with(&node, &actions, SelectionRule::Puct, 1.4, 25.0, &[0.1, 0.9]), Action::Play(1));
+    assert_eq!(select_with(&node, &actions, SelectionRule::Puct, 1.4, 25.0, &[0.9, 0.1]), Action::Play(0));
+
+    // The UCB rules try an unvisited action first, but PUCT gives one with
+    // no prior no bonus.
+    let actions = [Action::Play(0), Action::Play(1), Action::Play(2)];
+    assert_eq!(select_with(&node, &actions, SelectionRule::Ucb1, 1.4, 1.0, &[]), Action::Play(2));
+    assert_eq!(select_with(&node, &actions, SelectionRule::Ucb1Tuned, 1.4, 1.0, &[]), Action::Play(2));
+    assert_eq!(select_with(&node, &actions, SelectionRule::Puct, 1.4, 25.0, &[0.1, 0.9, 0.0]), Action::Play(1));
+}
+
+#[test]
+fn amaf_counts_the_actors_later_moves() {
+    let mut node = Node::new();
+    let moves = [
+        (Player::Alice, Action::Play(0)),
+        (Player::Bob, Action::Play(1)),
+        (Player::Alice, Action::Discard(2)),
+        (Player::Alice, Action::Play(0)),
+    ];
+    node.add_amaf_samples(Player::Alice, &moves, 20.0);
+    // Each of Alice's actions counts once, and Bob's not at all.
+    assert_eq!(node.actions[&Action::Play(0)].amaf_samples, 1.0);
+    assert_eq!(node.actions[&Action::Discard(2)].amaf_samples, 1.0);
+    assert!(!node.actions.contains_key(&Action::Play(1)));
+
+    // Playouts credit the root player's later moves at the root, so there
+    // are more AMAF samples there than playouts.
+    let mut rng = seeded_rng(13);
+    let mcts = MctsState::with_config(GameState::initial(&SAMPLE_DECK).current_view(), MctsConfig {
+        rave_equivalence: Some(10.0),
+        ..MctsConfig::default()
+    });
+    for _ in 0..50 {
+        let (updates, result) = mcts.run_playout(&mut rng);
+        mcts.update(updates, result);
+    }
+    mcts.with_root_node(|root| {
+        let samples: f64 = root.actions.values().map(|arrow| arrow.num_samples).sum();
+        let amaf_samples: f64 = root.actions.values().map(|arrow| arrow.amaf_samples).sum();
+        assert_eq!(samples, 50.0);
+        assert!(amaf_samples > samples);
+        for arrow in root.actions.values() {
+            assert!(arrow.amaf_samples >= arrow.num_samples);
+        }
+    });
+}
+
+#[test]
+fn rave_weight_falls_as_visits_grow() {
+    // Real samples of 0 against an AMAF mean of 25, so the blend is
+    // 25 * beta.
+    let mut node = Node::new();
+    node.add_amaf_samples(Player::Alice, &[(Player::Alice, Action::Play(0))], 25.0);
+    let mut last = 25.0;
+    for visits in 1..=100 {
+        node.add_sample(Action::Play(0), 0.0);
+        let blended = node.actions[&Action::Play(0)].blended_reward(0.0, visits as f64, Some(10.0));
+        assert!(blended < last);
+        last = blended;
+    }
+    // beta = sqrt(10 / 310) after 100 visits.
+    assert!((last - 25.0 * (10.0f64 / 310.0).sqrt()).abs() < 1e-9);
+    assert_eq!(node.actions[&Action::Play(0)].blended_reward(0.0, 100.0, None), 0.0);
+}
+
+#[test]
+fn heuristic_rollout_plays_known_cards_and_gives_new_clues() {
+    let compact = CompactState::from(&clued_position());
+    let legal_actions = compact.legal_actions();
+    let policy = HeuristicRollout { epsilon: 0.0 };
+    let mut rng = seeded_rng(14);
+    for _ in 0..10 {
+        assert_eq!(policy.choose_action(&compact, &legal_actions, &mut rng), Action::Play(1));
+    }
+
+    let hand = compact.hand(Player::Bob);
+    let one = compact.knowledge(hand[1]);
+    assert!(!clue_is_new(Clue::Rank(Rank::One), one));
+    assert!(!clue_is_new(Clue::Rank(Rank::Two), one));
+    assert!(clue_is_new(Clue::Suit(Suit::Yellow), one));
+    let red_one = compact.knowledge(hand[3]);
+    assert!(!clue_is_new(Clue::Suit(Suit::Red), red_one));
+    assert!(!clue_is_new(Clue::Rank(Rank::One), red_one));
+    assert!(clue_is_new(Clue::Rank(Rank::One), compact.knowledge(hand[0])));
+}
+
+// Plays uniformly, remembering every world it was asked to act in.
+#[derive(Debug, Default)]
+struct RecordingRollout {
+    worlds: Mutex<Vec<CompactState>>,
+}
+
+impl RolloutPolicy for RecordingRollout {
+    fn choose_action(&self, state: &CompactState, legal_actions: &[Action], rng: &mut dyn Rng) -> Action {
+        self.worlds.lock().unwrap().push(*state);
+        UniformRollout.choose_action(state, legal_actions, rng)
+    }
+}
+
+// The worlds the rollouts saw in playouts that all start from the real deck,
+// or from a fresh determinization of the root if from_deck is false.
+fn rollout_worlds(search_mode: SearchMode, from_deck: bool) -> Vec<CompactState> {
+    let mut rng = seeded_rng(15);
+    let state = GameState::initial(&SAMPLE_DECK);
+    let recorder = Arc::new(RecordingRollout::default());
+    let mcts = MctsState::with_config(state.current_view(), MctsConfig {
+        search_mode,
+        rollout: recorder.clone(),
+        ..MctsConfig::default()
+    });
+    for _ in 0..200 {
+        let (updates, result) = if from_deck {
+            mcts.run_playout_from(state.clone(), &mut rng)
+        } else {
+            mcts.run_playout(&mut rng)
+        };
+        mcts.update(updates, result);
+    }
+    let worlds = recorder.worlds.lock().unwrap().clone();
+    worlds
+}
+
+#[test]
+fn search_modes_resample_as_documented() {
+    let deck = CompactState::from(&GameState::initial(&SAMPLE_DECK));
+    let alice = |world: &CompactState| (0..4u8).map(|slot| world.cards[(slot * 4) as usize]).collect::<Vec<u8>>();
+
+    // PerPlayout plays every playout in the world it started from.
+    let worlds = rollout_worlds(SearchMode::PerPlayout, true);
+    assert!(worlds.iter().all(|world| world.cards == deck.cards));
+    // Without a starting world, each playout draws its own.
+    let worlds = rollout_worlds(SearchMode::PerPlayout, false);
+    assert!(worlds.iter().any(|world| world.cards != worlds[0].cards));
+
+    // PerStep redraws the hidden cards of each player to act below the
+    // root, but keeps the root player's hand from the starting world.
+    let worlds = rollout_worlds(SearchMode::PerStep, true);
+    assert!(worlds.iter().any(|world| world.cards != deck.cards));
+    assert!(worlds.iter().all(|world| alice(world) == alice(&deck)));
+}
+
+#[test]
+fn ismcts_keys_nodes_by_what_each_player_sees() {
+    // Swapping Alice's hand with the next cards in the deck changes nothing
+    // Alice can see, but changes what everyone else sees.
+    let deck = GameState::initial(&SAMPLE_DECK);
+    let mut swapped = deck.clone();
+    let swaps: HashMap<CardId, Card> = (0..4).flat_map(|slot| {
+        let (held, next) = (CardId(slot * 4), CardId(16 + slot));
+        vec![(held, deck.card_map[&next]), (next, deck.card_map[&held])]
+    }).collect();
+    swapped.assign_cards(&swaps);
+    assert_eq!(swapped.player_view_hash(Player::Alice), deck.player_view_hash(Player::Alice));
+    assert_ne!(swapped.player_view_hash(Player::Bob), deck.player_view_hash(Player::Bob));
+
+    let mut rng = seeded_rng(16);
+    let mut ismcts = IsmctsState::new(deck.current_view(), 1.4);
+    let mut keys = Vec::new();
+    for world in [&deck, &swapped].iter() {
+        let mut world_keys = Vec::new();
+        for _ in 0..20 {
+            let (updates, result) = ismcts.run_playout_from((*world).clone(), &mut rng);
+            // Every player is in their tree for their first move, so the
+            // first four steps follow the game from the root.
+            let mut state = (*world).clone();
+            for &(player, hash, action) in updates.iter().take(4) {
+                assert_eq!(player, state.current_player());
+                assert_eq!(hash, state.player_view_hash(player));
+                assert_eq!(hash, state.player_view(player).fingerprint_hash());
+                world_keys.push((player, hash));
+                state.act(action);
+            }
+            ismcts.update(updates, result);
+        }
+        keys.push(world_keys);
+    }
+
+    // Alice's root is one node for both deals, while her partners' nodes
+    // never coincide across them.
+    let alice_keys = |world_keys: &[(Player, u64)]| -> Vec<u64> {
+        world_keys.iter().filter(|&&(p, _)| p == Player::Alice).map(|&(_, hash)| hash).collect()
+    };
+    let root = deck.player_view_hash(Player::Alice);
+    assert!(alice_keys(&keys[0]).iter().chain(alice_keys(&keys[1]).iter()).all(|&hash| hash == root));
+    for &(player, hash) in keys[0].iter().filter(|&&(p, _)| p != Player::Alice) {
+        assert!(!keys[1].contains(&(player, hash)));
+    }
+}
+
+#[test]
+fn expectimax_is_legal_reproducible_and_takes_safe_plays() {
+    let mut agent = ExpectimaxAgent::default();
+    agent.set_samples(4);
+
+    let view = heuristic_position(20).current_view();
+    let action = agent.choose_action(&view, &mut seeded_rng(17));
+    assert!(view.legal_actions().contains(&action));
+    assert_eq!(agent.choose_action(&view, &mut seeded_rng(17)), action);
+
+    // Bob knows his second card is a playable one.
+    let view = clued_position().current_view();
+    assert_eq!(agent.choose_action(&view, &mut seeded_rng(18)), Action::Play(1));
+}
+
+#[test]
+fn parallel_search_settles_every_virtual_loss() {
+    let mut mcts = MctsState::new(heuristic_position(20).current_view(), 1.4);
+    let budget = SearchBudget {
+        max_playouts: Some(2000),
+        ..SearchBudget::default()
+    };
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+    let report = pool.install(|| mcts.search(&budget, None));
+    assert_eq!(report.stop_reason, StopReason::PlayoutLimit);
+    assert_eq!(report.playouts, 2000);
+
+    let mut nodes = 0;
+    let mut samples = Vec::new();
+    mcts.for_each_node(|node| {
+        nodes += 1;
+        assert_eq!(node.virtual_losses, 0.0);
+        assert!(node.actions.values().all(|arrow| arrow.virtual_losses == 0.0));
+        let arrow_samples: f64 = node.actions.values().map(|arrow| arrow.num_samples).sum();
+        assert_eq!(arrow_samples, node.total_samples);
+        samples.push(node.total_samples);
+    });
+    assert_eq!(nodes, mcts.num_nodes());
+    // Every playout passes the root and adds at most one node, unless
+    // another thread added the same node first. Every added node has
+    // recorded the playout that added it.
+    assert_eq!(samples[0], 2000.0);
+    assert!(nodes > 1 && nodes <= 2001);
+    assert!(samples.iter().all(|&n| n >= 1.0));
+    let visits: usize = mcts.root_report().iter().map(|stats| stats.visits).sum();
+    assert_eq!(visits, 2000);
+}
+
+// The end of a game in which every suit is complete but red, which stands
+// at four. The last red five is in Dave's hand beside three cards that are
+// already played, and everything else is played, discarded or dead in a
+// hand. Cathy is to act and Dave, who drew the last card, acts after her.
+fn red_five_endgame(clues: u8) -> GameState {
+    let card = |suit, rank| Card { suit, rank };
+    let (r, g, b, y, p) = (Suit::Red, Suit::Green, Suit::Blue, Suit::Yellow, Suit::Purple);
+    let hands = [
+        [card(p, Rank::Three), card(y, Rank::Four), card(g, Rank::Four), card(b, Rank::Four)],
+        [card(p, Rank::Two), card(y, Rank::Three), card(g, Rank::Three), card(b, Rank::Three)],
+        [card(p, Rank::One), card(y, Rank::Two), card(g, Rank::Two), card(b, Rank::Two)],
+        [card(r, Rank::Five), card(y, Rank::One), card(g, Rank::One), card(b, Rank::One)],
+    ];
+    let mut deck = Vec::new();
+    for slot in 0..4 {
+        for hand in hands.iter() {
+            deck.push(hand[slot]);
+        }
+    }
+    for &suit in [r, g, b, y, p].iter() {
+        for &rank in [Rank::One, Rank::Two, Rank::Three, Rank::Four, Rank::Five].iter() {
+            if suit != r || rank != Rank::Five {
+                deck.push(card(suit, rank));
+            }
+        }
+    }
+    for &c in [card(y, Rank::One), card(g, Rank::One), card(b, Rank::One), card(p, Rank::One),
+               card(r, Rank::One), card(r, Rank::One), card(r, Rank::Two), card(r, Rank::Three),
+               card(r, Rank::Four), card(p, Rank::Four)].iter() {
+        deck.push(c);
+    }
+    assert_eq!(deck.len(), 50);
+
+    let mut state = GameState::initial(&deck);
+    state.played_cards = (16..40).map(CardId).collect();
+    state.discarded_cards = (40..50).map(CardId).collect();
+    for &suit in [r, g, b, y, p].iter() {
+        state.piles.insert(suit, if suit == r { Rank::Four } else { Rank::Five });
+    }
+    state.deck_size = 0;
+    state.next_card_id = CardId(50);
+    state.current_turn = Player::Cathy;
+    state.final_turn = Some(Player::Dave);
+    state.clues = clues;
+    state
+}
+
+#[test]
+fn endgame_solver_decides_from_each_players_view() {
+    use endgame::{EndgameConfig, EndgameSolver};
+    let solve = |state: &GameState| {
+        let mut solver = EndgameSolver::new(EndgameConfig::default(), Objective::ExpectedScore);
+        solver.solve(&state.current_view()).unwrap()
+    };
+
+    // Cathy can count her own hand but not order it, so 4! deals fit her
+    // view. Cluing red or fives shows Dave which card to play, so the team
+    // scores 25 whatever Cathy holds.
+    let mut state = red_five_endgame(1);
+    let solution = solve(&state);
+    assert_eq!(solution.deals, 24);
+    let clues = [Action::Clue(Player::Dave, Clue::Suit(Suit::Red)), Action::Clue(Player::Dave, Clue::Rank(Rank::Five))];
+    assert!(clues.contains(&solution.action));
+    assert_eq!(solution.expected_reward, 25.0);
+
+    // Left unclued, Dave knows he holds the red five but not where, and a
+    // blind play finds it in one ordering of his hand out of four. A solver
+    // that let him see his own hand would score 25 here.
+    state.current_turn = Player::Dave;
+    let solution = solve(&state);
+    assert_eq!(solution.deals, 24);
+    assert_eq!(solution.expected_reward, 24.25);
+}